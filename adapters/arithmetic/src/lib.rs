@@ -1,4 +1,5 @@
-use core::GameAdapter;
+use core::{GameAdapter, Grade, Prompt};
+use std::time::Duration;
 
 #[derive(Debug, Clone, Default)]
 pub struct ArithmeticAdapter;
@@ -8,16 +9,16 @@ impl GameAdapter for ArithmeticAdapter {
         "arithmetic"
     }
 
-    fn next_prompt(&self, seed: u64) -> String {
+    fn next_prompt(&self, seed: u64) -> Prompt {
         let left = (seed % 12 + 1) as i32;
         let right = ((seed / 3) % 12 + 1) as i32;
-        format!("{left} + {right}")
+        Prompt::new(format!("{left} + {right}"), left + right)
+            .with_metadata("operation", "addition")
     }
 
-    fn is_correct(&self, prompt: &str, attempt: &str) -> bool {
-        let expected = eval_sum_prompt(prompt);
-        match attempt.trim().parse::<i32>() {
-            Ok(value) => expected == Some(value),
+    fn is_correct(&self, prompt: &Prompt, attempt: &str) -> bool {
+        match attempt.trim().parse::<i64>() {
+            Ok(value) => prompt.answer.as_i64() == Some(value),
             Err(_) => false,
         }
     }
@@ -26,16 +27,32 @@ impl GameAdapter for ArithmeticAdapter {
         raw_input.trim().to_string()
     }
 
-    fn score_for_prompt(&self, _prompt: &str) -> f32 {
+    fn score_for_prompt(&self, _prompt: &Prompt) -> f32 {
         5.0
     }
-}
 
-fn eval_sum_prompt(prompt: &str) -> Option<i32> {
-    let mut parts = prompt.split('+').map(str::trim);
-    let left = parts.next()?.parse::<i32>().ok()?;
-    let right = parts.next()?.parse::<i32>().ok()?;
-    Some(left + right)
+    fn round_duration(&self) -> Option<Duration> {
+        Some(Duration::from_secs(20))
+    }
+
+    fn grade(&self, prompt: &Prompt, attempt: &str) -> Grade {
+        let (Ok(value), Some(expected)) = (attempt.trim().parse::<i64>(), prompt.answer.as_i64())
+        else {
+            return Grade::Incorrect {
+                feedback: "Answers must be whole numbers".to_string(),
+            };
+        };
+        match (value - expected).abs() {
+            0 => Grade::Correct,
+            1 => Grade::Partial {
+                fraction: 0.5,
+                feedback: "Close! You're off by one".to_string(),
+            },
+            _ => Grade::Incorrect {
+                feedback: "Not quite, try again".to_string(),
+            },
+        }
+    }
 }
 
 #[cfg(test)]
@@ -45,7 +62,28 @@ mod tests {
     #[test]
     fn validates_expected_sum() {
         let adapter = ArithmeticAdapter;
-        assert!(adapter.is_correct("2 + 9", "11"));
-        assert!(!adapter.is_correct("2 + 9", "12"));
+        let prompt = adapter.next_prompt(25);
+        assert_eq!(prompt.display, "2 + 9");
+        assert!(adapter.is_correct(&prompt, "11"));
+        assert!(!adapter.is_correct(&prompt, "12"));
+    }
+
+    #[test]
+    fn grades_near_misses_as_partial() {
+        let adapter = ArithmeticAdapter;
+        let prompt = adapter.next_prompt(25);
+        assert_eq!(adapter.grade(&prompt, " 11 "), Grade::Correct);
+        assert!(matches!(
+            adapter.grade(&prompt, "12"),
+            Grade::Partial { fraction, .. } if fraction == 0.5
+        ));
+        assert!(matches!(
+            adapter.grade(&prompt, "20"),
+            Grade::Incorrect { .. }
+        ));
+        assert!(matches!(
+            adapter.grade(&prompt, "eleven"),
+            Grade::Incorrect { .. }
+        ));
     }
 }
@@ -1,4 +1,5 @@
-use core::GameAdapter;
+use core::{GameAdapter, Grade, Prompt};
+use std::time::Duration;
 
 const WORDS: &[&str] = &[
     "adventure",
@@ -332,21 +333,46 @@ impl GameAdapter for KeyboardingAdapter {
         "keyboarding"
     }
 
-    fn next_prompt(&self, seed: u64) -> String {
+    fn next_prompt(&self, seed: u64) -> Prompt {
         let idx = (seed as usize) % WORDS.len();
-        WORDS[idx].to_string()
+        Prompt::new(WORDS[idx], WORDS[idx])
     }
 
-    fn is_correct(&self, prompt: &str, attempt: &str) -> bool {
-        prompt == attempt.trim()
+    fn is_correct(&self, prompt: &Prompt, attempt: &str) -> bool {
+        prompt.answer.as_str() == Some(attempt.trim())
     }
 
     fn normalize_progress(&self, raw_input: &str) -> String {
         raw_input.to_string()
     }
 
-    fn score_for_prompt(&self, prompt: &str) -> f32 {
-        (prompt.len() as f32 / 3.0).max(4.0)
+    fn score_for_prompt(&self, prompt: &Prompt) -> f32 {
+        (prompt.display.len() as f32 / 3.0).max(4.0)
+    }
+
+    fn round_duration(&self) -> Option<Duration> {
+        Some(Duration::from_secs(30))
+    }
+
+    fn grade(&self, prompt: &Prompt, attempt: &str) -> Grade {
+        let Some(expected) = prompt.answer.as_str() else {
+            return Grade::Incorrect {
+                feedback: "Incorrect answer".to_string(),
+            };
+        };
+        let attempt = attempt.trim();
+        if attempt == expected {
+            Grade::Correct
+        } else if attempt.eq_ignore_ascii_case(expected) {
+            Grade::Partial {
+                fraction: 0.5,
+                feedback: "Check your capitalization".to_string(),
+            }
+        } else {
+            Grade::Incorrect {
+                feedback: "That doesn't match the prompt".to_string(),
+            }
+        }
     }
 }
 
@@ -357,7 +383,23 @@ mod tests {
     #[test]
     fn validates_exact_word_match() {
         let adapter = KeyboardingAdapter;
-        assert!(adapter.is_correct("rust", "rust"));
-        assert!(!adapter.is_correct("rust", "Rust"));
+        let prompt = Prompt::new("rust", "rust");
+        assert!(adapter.is_correct(&prompt, "rust"));
+        assert!(!adapter.is_correct(&prompt, "Rust"));
+    }
+
+    #[test]
+    fn grades_capitalization_mistakes_as_partial() {
+        let adapter = KeyboardingAdapter;
+        let prompt = Prompt::new("rust", "rust");
+        assert_eq!(adapter.grade(&prompt, "rust"), Grade::Correct);
+        assert!(matches!(
+            adapter.grade(&prompt, "Rust"),
+            Grade::Partial { .. }
+        ));
+        assert!(matches!(
+            adapter.grade(&prompt, "rush"),
+            Grade::Incorrect { .. }
+        ));
     }
 }
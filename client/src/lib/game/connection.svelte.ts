@@ -1,5 +1,6 @@
 import { browser } from '$app/environment';
 import {
+	PROTOCOL_VERSION,
 	decodeServerMessage,
 	type ClientMessage,
 	type RoomSnapshot,
@@ -58,11 +59,14 @@ function normalizeRoomCode(value: string): string {
 export const gs = $state({
 	phase: 'pregame' as ConnectionPhase,
 	playerId: null as number | null,
+	/** Watching the room without a seat, after a `spectateRoom` request. */
+	spectating: false,
 	room: null as RoomSnapshot | null,
 	roomCode: '',
 	gameKey: '',
 	minEatableSize: 0,
 	promptInput: '',
+	roundEndsAtMs: null as number | null,
 	latestRoundSummary: '',
 	latestRoundSummaryColor: '',
 	errorMessage: '',
@@ -75,6 +79,8 @@ export const gs = $state({
 let socket: WebSocket | null = null;
 let welcomeCallback: ((roomCode: string) => void) | null = null;
 let disconnectCallback: (() => void) | null = null;
+/** Set when another tab took over this seat, so we don't try to take it back. */
+let sessionReplaced = false;
 
 export function setOnWelcome(fn: ((roomCode: string) => void) | null): void {
 	welcomeCallback = fn;
@@ -101,6 +107,15 @@ function handleServerMessage(message: ServerMessage): void {
 			saveRejoinToken(message.roomCode, message.rejoinToken);
 			welcomeCallback?.(message.roomCode);
 			break;
+		case 'spectatorWelcome':
+			gs.playerId = null;
+			gs.spectating = true;
+			gs.gameKey = message.gameKey;
+			gs.minEatableSize = message.minEatableSize;
+			gs.roomCode = message.roomCode;
+			gs.phase = 'ingame';
+			welcomeCallback?.(message.roomCode);
+			break;
 		case 'roomState':
 			gs.room = message.room;
 			if (message.room.matchWinner) {
@@ -119,6 +134,12 @@ function handleServerMessage(message: ServerMessage): void {
 				};
 			}
 			gs.promptInput = '';
+			gs.roundEndsAtMs = message.roundEndsAtMs;
+			break;
+		case 'roundExpired':
+			gs.roundEndsAtMs = null;
+			gs.latestRoundSummary = `Time's up! The answer was ${message.answer}`;
+			gs.latestRoundSummaryColor = '';
 			break;
 		case 'raceProgress':
 			if (!gs.room) break;
@@ -129,6 +150,29 @@ function handleServerMessage(message: ServerMessage): void {
 				)
 			};
 			break;
+		case 'sessionReplaced':
+			sessionReplaced = true;
+			gs.errorMessage = 'This seat was taken over by another tab or device';
+			break;
+		case 'playerLeft':
+			if (!gs.room) break;
+			gs.room = {
+				...gs.room,
+				players: gs.room.players.filter((p) => p.id !== message.playerId),
+				eliminated: gs.room.eliminated.filter((p) => p.id !== message.playerId)
+			};
+			break;
+		case 'raceProgressBatch': {
+			if (!gs.room) break;
+			const latest = new Map(message.progress.map((entry) => [entry.playerId, entry.text]));
+			gs.room = {
+				...gs.room,
+				players: gs.room.players.map((p) =>
+					latest.has(p.id) ? { ...p, progress: latest.get(p.id) ?? p.progress } : p
+				)
+			};
+			break;
+		}
 		case 'roundResult':
 			if (!gs.room) break;
 			{
@@ -137,6 +181,19 @@ function handleServerMessage(message: ServerMessage): void {
 				gs.latestRoundSummaryColor = winner?.color ?? '';
 			}
 			break;
+		case 'attemptRejected': {
+			const changes = [
+				message.growthAwarded > 0 ? `+${message.growthAwarded.toFixed(1)}` : '',
+				message.sizeLost > 0 ? `-${message.sizeLost.toFixed(1)}` : ''
+			].filter(Boolean);
+			gs.latestRoundSummary = message.eliminated
+				? 'You shrank too small and were eliminated'
+				: changes.length > 0
+					? `${message.feedback} (${changes.join(' / ')} size)`
+					: message.feedback;
+			gs.latestRoundSummaryColor = '';
+			break;
+		}
 		case 'error':
 			gs.errorMessage = message.message;
 			break;
@@ -150,6 +207,7 @@ export function connect(
 		playerName?: string;
 		gameMode?: GameMode;
 		rejoinToken?: string;
+		spectate?: boolean;
 	}
 ): void {
 	if (gs.phase === 'connecting') return;
@@ -158,6 +216,7 @@ export function connect(
 	gs.latestRoundSummary = '';
 	gs.latestRoundSummaryColor = '';
 	gs.phase = 'connecting';
+	gs.spectating = false;
 	gs.socketState = 'connecting';
 	gs.lastSocketDetail = '';
 	socket?.close();
@@ -168,6 +227,7 @@ export function connect(
 		wsUrl
 	});
 
+	sessionReplaced = false;
 	try {
 		socket = new WebSocket(wsUrl);
 	} catch {
@@ -178,8 +238,15 @@ export function connect(
 
 	socket.onopen = () => {
 		gs.socketState = 'open';
+		sendClientMessage({
+			type: 'hello',
+			protocolVersion: PROTOCOL_VERSION,
+			capabilities: ['progressBatch']
+		});
 		if (opts?.rejoinToken) {
 			sendClientMessage({ type: 'rejoinRoom', rejoinToken: opts.rejoinToken });
+		} else if (opts?.spectate && opts.roomCode) {
+			sendClientMessage({ type: 'spectateRoom', roomCode: normalizeRoomCode(opts.roomCode) });
 		} else {
 			sendClientMessage({
 				type: 'joinOrCreateRoom',
@@ -205,7 +272,7 @@ export function connect(
 	socket.onclose = (event: CloseEvent) => {
 		gs.socketState = 'closed';
 		gs.lastSocketDetail = `closed code=${event.code} reason=${event.reason || '(none)'}`;
-		const wasActive = gs.phase !== 'pregame';
+		const wasActive = gs.phase !== 'pregame' && !sessionReplaced;
 		if (wasActive) {
 			gs.errorMessage = 'Disconnected from server';
 		}
@@ -222,6 +289,7 @@ export function disconnect(): void {
 	socket = null;
 	gs.phase = 'pregame';
 	gs.room = null;
+	gs.roundEndsAtMs = null;
 }
 
 export function socketStateLabel(): string {
@@ -240,3 +308,11 @@ export function handlePromptInput(value: string): void {
 export function submitPrompt(): void {
 	sendClientMessage({ type: 'submitAttempt', text: gs.promptInput });
 }
+
+export function startMatch(): void {
+	sendClientMessage({ type: 'startMatch' });
+}
+
+export function requestRematch(): void {
+	sendClientMessage({ type: 'requestRematch' });
+}
@@ -6,6 +6,13 @@ describe('decodeServerMessage', () => {
 		expect(decodeServerMessage('bad-json')).toBeNull();
 	});
 
+	it('parses hello', () => {
+		const parsed = decodeServerMessage(
+			JSON.stringify({ type: 'hello', protocolVersion: 1, capabilities: [] })
+		);
+		expect(parsed?.type).toBe('hello');
+	});
+
 	it('parses welcome', () => {
 		const parsed = decodeServerMessage(
 			JSON.stringify({
@@ -20,6 +27,19 @@ describe('decodeServerMessage', () => {
 		expect(parsed?.type).toBe('welcome');
 	});
 
+	it('parses spectatorWelcome', () => {
+		const parsed = decodeServerMessage(
+			JSON.stringify({
+				type: 'spectatorWelcome',
+				spectatorId: 7,
+				roomCode: 'ABCD',
+				gameKey: 'keyboarding',
+				minEatableSize: 18
+			})
+		);
+		expect(parsed?.type).toBe('spectatorWelcome');
+	});
+
 	it('parses roomState', () => {
 		const parsed = decodeServerMessage(
 			JSON.stringify({
@@ -33,13 +53,33 @@ describe('decodeServerMessage', () => {
 							size: 14.2,
 							color: '#38bdf8',
 							connected: true,
-							progress: 'he'
+							progress: 'he',
+							team: null,
+							latencyMs: 48
+						}
+					],
+					eliminated: [
+						{
+							id: 2,
+							name: 'Bob',
+							color: '#f472b6',
+							connected: true,
+							eliminatedInRound: 1,
+							eatenBy: 1
 						}
 					],
 					prompt: 'hello',
 					roundId: 1,
-					matchWinner: null
-				}
+					matchWinner: null,
+					phase: 'playing',
+					hostId: 1,
+					matchNumber: 1,
+					teamMode: false,
+					teams: [],
+					winningTeam: null,
+					maxPlayers: 8
+				},
+				seq: 1
 			})
 		);
 		expect(parsed?.type).toBe('roomState');
@@ -51,12 +91,25 @@ describe('decodeServerMessage', () => {
 				type: 'promptState',
 				roomCode: 'ABCD',
 				roundId: 2,
-				prompt: 'world'
+				prompt: 'world',
+				roundEndsAtMs: 1700000030000
 			})
 		);
 		expect(parsed?.type).toBe('promptState');
 	});
 
+	it('parses roundExpired', () => {
+		const parsed = decodeServerMessage(
+			JSON.stringify({
+				type: 'roundExpired',
+				roomCode: 'ABCD',
+				roundId: 2,
+				answer: 'world'
+			})
+		);
+		expect(parsed?.type).toBe('roundExpired');
+	});
+
 	it('parses raceProgress', () => {
 		const parsed = decodeServerMessage(
 			JSON.stringify({
@@ -84,11 +137,28 @@ describe('decodeServerMessage', () => {
 		expect(parsed?.type).toBe('roundResult');
 	});
 
+	it('parses attemptRejected', () => {
+		const parsed = decodeServerMessage(
+			JSON.stringify({
+				type: 'attemptRejected',
+				roomCode: 'ABCD',
+				roundId: 2,
+				feedback: 'Close: 3 of 5 characters match',
+				growthAwarded: 1.2,
+				sizeLost: 0,
+				lockedUntilMs: null,
+				eliminated: false
+			})
+		);
+		expect(parsed?.type).toBe('attemptRejected');
+	});
+
 	it('parses error', () => {
 		const parsed = decodeServerMessage(
 			JSON.stringify({
 				type: 'error',
-				message: 'boom'
+				code: 'roomFull',
+				message: 'Room is full'
 			})
 		);
 		expect(parsed?.type).toBe('error');
@@ -1,3 +1,6 @@
+/** Must match `PROTOCOL_VERSION` in core::protocol. */
+export const PROTOCOL_VERSION = 1;
+
 export type PlayerSnapshot = {
 	id: number;
 	name: string;
@@ -5,23 +8,90 @@ export type PlayerSnapshot = {
 	color: string;
 	connected: boolean;
 	progress: string;
+	team: string | null;
+	/** Round trip of the last server ping, while connected. */
+	latencyMs: number | null;
+};
+
+export type TeamSnapshot = {
+	name: string;
+	size: number;
+	memberIds: number[];
+};
+
+export type EliminatedSnapshot = {
+	id: number;
+	name: string;
+	color: string;
+	connected: boolean;
+	eliminatedInRound: number;
+	eatenBy: number | null;
 };
 
+export type RoomPhase = 'lobby' | 'playing' | 'finished';
+
 export type RoomSnapshot = {
 	roomCode: string;
 	players: PlayerSnapshot[];
+	eliminated: EliminatedSnapshot[];
 	prompt: string;
 	roundId: number;
 	matchWinner: number | null;
+	phase: RoomPhase;
+	hostId: number | null;
+	matchNumber: number;
+	teamMode: boolean;
+	teams: TeamSnapshot[];
+	winningTeam: string | null;
+	maxPlayers: number;
 };
 
+export type ErrorCode =
+	| 'invalidMessage'
+	| 'roomNotFound'
+	| 'roomFull'
+	| 'matchInProgress'
+	| 'unknownGameMode'
+	| 'nameTaken'
+	| 'invalidWinCondition'
+	| 'invalidTeam'
+	| 'invalidRejoinToken'
+	| 'playerNotInRoom'
+	| 'roomClosed'
+	| 'spectatorCannotPlay'
+	| 'eliminatedCannotPlay'
+	| 'notHost'
+	| 'matchAlreadyStarted'
+	| 'helloRequired'
+	| 'unsupportedProtocolVersion'
+	| 'rateLimited'
+	| 'textTooLong'
+	| 'messageTooLarge';
+
+export type ErrorDetails =
+	| { kind: 'parse'; line: number; column: number }
+	| { kind: 'protocolVersion'; minSupported: number; maxSupported: number };
+
 export type ClientMessage =
-	| { type: 'joinOrCreateRoom'; playerName?: string; roomCode?: string; gameMode?: string }
+	| { type: 'hello'; protocolVersion: number; capabilities: string[] }
+	| {
+			type: 'joinOrCreateRoom';
+			playerName?: string;
+			roomCode?: string;
+			gameMode?: string;
+			team?: string;
+			maxPlayers?: number;
+	  }
 	| { type: 'rejoinRoom'; rejoinToken: string }
+	| { type: 'spectateRoom'; roomCode: string }
+	| { type: 'startMatch' }
+	| { type: 'requestRoomState' }
+	| { type: 'requestRematch' }
 	| { type: 'inputUpdate'; text: string }
 	| { type: 'submitAttempt'; text: string };
 
 export type ServerMessage =
+	| { type: 'hello'; protocolVersion: number; capabilities: string[] }
 	| {
 			type: 'welcome';
 			playerId: number;
@@ -30,9 +100,31 @@ export type ServerMessage =
 			minEatableSize: number;
 			rejoinToken: string;
 	  }
-	| { type: 'roomState'; room: RoomSnapshot }
-	| { type: 'promptState'; roomCode: string; roundId: number; prompt: string }
+	| {
+			type: 'spectatorWelcome';
+			spectatorId: number;
+			roomCode: string;
+			gameKey: string;
+			minEatableSize: number;
+	  }
+	| { type: 'roomState'; room: RoomSnapshot; seq: number }
+	| {
+			type: 'promptState';
+			roomCode: string;
+			roundId: number;
+			prompt: string;
+			/** Server clock time the round expires, when rounds are timed. */
+			roundEndsAtMs: number | null;
+	  }
+	| { type: 'roundExpired'; roomCode: string; roundId: number; answer: string }
 	| { type: 'raceProgress'; roomCode: string; playerId: number; text: string }
+	| {
+			type: 'raceProgressBatch';
+			roomCode: string;
+			progress: { playerId: number; text: string }[];
+	  }
+	| { type: 'playerLeft'; roomCode: string; playerId: number }
+	| { type: 'sessionReplaced'; roomCode: string }
 	| {
 			type: 'roundResult';
 			roomCode: string;
@@ -42,7 +134,17 @@ export type ServerMessage =
 			consumedPlayerIds: number[];
 			matchWinner: number | null;
 	  }
-	| { type: 'error'; message: string };
+	| {
+			type: 'attemptRejected';
+			roomCode: string;
+			roundId: number;
+			feedback: string;
+			growthAwarded: number;
+			sizeLost: number;
+			lockedUntilMs: number | null;
+			eliminated: boolean;
+	  }
+	| { type: 'error'; code: ErrorCode; message: string; details?: ErrorDetails };
 
 function isObject(value: unknown): value is Record<string, unknown> {
 	return typeof value === 'object' && value !== null;
@@ -56,18 +158,60 @@ function isPlayerSnapshot(value: unknown): value is PlayerSnapshot {
 		typeof value.size === 'number' &&
 		typeof value.color === 'string' &&
 		typeof value.connected === 'boolean' &&
-		typeof value.progress === 'string'
+		typeof value.progress === 'string' &&
+		(value.team === null || typeof value.team === 'string') &&
+		(value.latencyMs === null || typeof value.latencyMs === 'number')
+	);
+}
+
+function isEliminatedSnapshot(value: unknown): value is EliminatedSnapshot {
+	if (!isObject(value)) return false;
+	return (
+		typeof value.id === 'number' &&
+		typeof value.name === 'string' &&
+		typeof value.color === 'string' &&
+		typeof value.connected === 'boolean' &&
+		typeof value.eliminatedInRound === 'number' &&
+		(value.eatenBy === null || typeof value.eatenBy === 'number')
+	);
+}
+
+function isTeamSnapshot(value: unknown): value is TeamSnapshot {
+	if (!isObject(value) || !Array.isArray(value.memberIds)) return false;
+	return (
+		typeof value.name === 'string' &&
+		typeof value.size === 'number' &&
+		value.memberIds.every((id) => typeof id === 'number')
 	);
 }
 
+function isRoomPhase(value: unknown): value is RoomPhase {
+	return value === 'lobby' || value === 'playing' || value === 'finished';
+}
+
 function isRoomSnapshot(value: unknown): value is RoomSnapshot {
-	if (!isObject(value) || !Array.isArray(value.players)) return false;
+	if (
+		!isObject(value) ||
+		!Array.isArray(value.players) ||
+		!Array.isArray(value.eliminated) ||
+		!Array.isArray(value.teams)
+	) {
+		return false;
+	}
 	return (
 		typeof value.roomCode === 'string' &&
 		typeof value.prompt === 'string' &&
 		typeof value.roundId === 'number' &&
 		(value.matchWinner === null || typeof value.matchWinner === 'number') &&
-		value.players.every(isPlayerSnapshot)
+		isRoomPhase(value.phase) &&
+		(value.hostId === null || typeof value.hostId === 'number') &&
+		typeof value.matchNumber === 'number' &&
+		typeof value.teamMode === 'boolean' &&
+		typeof value.maxPlayers === 'number' &&
+		(value.winningTeam === null || typeof value.winningTeam === 'string') &&
+		value.players.every(isPlayerSnapshot) &&
+		value.eliminated.every(isEliminatedSnapshot) &&
+		value.teams.every(isTeamSnapshot)
 	);
 }
 
@@ -76,6 +220,12 @@ function isServerMessage(value: unknown): value is ServerMessage {
 		return false;
 	}
 	switch (value.type) {
+		case 'hello':
+			return (
+				typeof value.protocolVersion === 'number' &&
+				Array.isArray(value.capabilities) &&
+				value.capabilities.every((capability) => typeof capability === 'string')
+			);
 		case 'welcome':
 			return (
 				typeof value.playerId === 'number' &&
@@ -84,13 +234,27 @@ function isServerMessage(value: unknown): value is ServerMessage {
 				typeof value.minEatableSize === 'number' &&
 				typeof value.rejoinToken === 'string'
 			);
+		case 'spectatorWelcome':
+			return (
+				typeof value.spectatorId === 'number' &&
+				typeof value.roomCode === 'string' &&
+				typeof value.gameKey === 'string' &&
+				typeof value.minEatableSize === 'number'
+			);
 		case 'roomState':
-			return isRoomSnapshot(value.room);
+			return typeof value.seq === 'number' && isRoomSnapshot(value.room);
 		case 'promptState':
 			return (
 				typeof value.roomCode === 'string' &&
 				typeof value.roundId === 'number' &&
-				typeof value.prompt === 'string'
+				typeof value.prompt === 'string' &&
+				(value.roundEndsAtMs === null || typeof value.roundEndsAtMs === 'number')
+			);
+		case 'roundExpired':
+			return (
+				typeof value.roomCode === 'string' &&
+				typeof value.roundId === 'number' &&
+				typeof value.answer === 'string'
 			);
 		case 'raceProgress':
 			return (
@@ -98,6 +262,21 @@ function isServerMessage(value: unknown): value is ServerMessage {
 				typeof value.playerId === 'number' &&
 				typeof value.text === 'string'
 			);
+		case 'raceProgressBatch':
+			return (
+				typeof value.roomCode === 'string' &&
+				Array.isArray(value.progress) &&
+				value.progress.every(
+					(entry) =>
+						isObject(entry) &&
+						typeof entry.playerId === 'number' &&
+						typeof entry.text === 'string'
+				)
+			);
+		case 'sessionReplaced':
+			return typeof value.roomCode === 'string';
+		case 'playerLeft':
+			return typeof value.roomCode === 'string' && typeof value.playerId === 'number';
 		case 'roundResult':
 			return (
 				typeof value.roomCode === 'string' &&
@@ -108,8 +287,18 @@ function isServerMessage(value: unknown): value is ServerMessage {
 				value.consumedPlayerIds.every((id) => typeof id === 'number') &&
 				(value.matchWinner === null || typeof value.matchWinner === 'number')
 			);
+		case 'attemptRejected':
+			return (
+				typeof value.roomCode === 'string' &&
+				typeof value.roundId === 'number' &&
+				typeof value.feedback === 'string' &&
+				typeof value.growthAwarded === 'number' &&
+				typeof value.sizeLost === 'number' &&
+				(value.lockedUntilMs === null || typeof value.lockedUntilMs === 'number') &&
+				typeof value.eliminated === 'boolean'
+			);
 		case 'error':
-			return typeof value.message === 'string';
+			return typeof value.message === 'string' && typeof value.code === 'string';
 		default:
 			return false;
 	}
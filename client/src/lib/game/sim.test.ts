@@ -8,8 +8,26 @@ describe('nextBlobLayout', () => {
 
 	it('provides coordinates for each player', () => {
 		const players = [
-			{ id: 1, name: 'A', size: 20, color: '#fff', connected: true, progress: '' },
-			{ id: 2, name: 'B', size: 10, color: '#000', connected: true, progress: '' }
+			{
+				id: 1,
+				name: 'A',
+				size: 20,
+				color: '#fff',
+				connected: true,
+				progress: '',
+				team: null,
+				latencyMs: null
+			},
+			{
+				id: 2,
+				name: 'B',
+				size: 10,
+				color: '#000',
+				connected: true,
+				progress: '',
+				team: null,
+				latencyMs: null
+			}
 		];
 		const next = nextBlobLayout(players, {}, 16, 800, 600);
 		expect(Object.keys(next)).toHaveLength(2);
@@ -1,12 +1,81 @@
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
+use std::time::Duration;
+
+/// A prompt handed out by an adapter for a single round.
+///
+/// Only `display` and `metadata` are ever sent to clients; `answer` stays on
+/// the server so adapters can grade attempts without re-parsing the display
+/// text.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Prompt {
+    pub display: String,
+    pub answer: Value,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Prompt {
+    pub fn new(display: impl Into<String>, answer: impl Into<Value>) -> Self {
+        Self {
+            display: display.into(),
+            answer: answer.into(),
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// Human-readable answer, revealed to clients once a round is over.
+    pub fn answer_text(&self) -> String {
+        match &self.answer {
+            Value::String(answer) => answer.clone(),
+            answer => answer.to_string(),
+        }
+    }
+}
+
+/// Outcome of grading a single attempt against the current prompt.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Grade {
+    Correct,
+    /// Close enough to earn `fraction` (0.0..=1.0) of the round's growth,
+    /// without winning the round.
+    Partial {
+        fraction: f32,
+        feedback: String,
+    },
+    Incorrect {
+        feedback: String,
+    },
+}
 
 pub trait GameAdapter: Send + Sync + 'static {
     fn game_key(&self) -> &'static str;
-    fn next_prompt(&self, seed: u64) -> String;
-    fn is_correct(&self, prompt: &str, attempt: &str) -> bool;
+    fn next_prompt(&self, seed: u64) -> Prompt;
+    fn is_correct(&self, prompt: &Prompt, attempt: &str) -> bool;
     fn normalize_progress(&self, raw_input: &str) -> String;
-    fn score_for_prompt(&self, prompt: &str) -> f32;
+    fn score_for_prompt(&self, prompt: &Prompt) -> f32;
+
+    /// How long a round may run before it expires unanswered. `None` lets
+    /// rounds run until someone answers correctly.
+    fn round_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    fn grade(&self, prompt: &Prompt, attempt: &str) -> Grade {
+        if self.is_correct(prompt, attempt) {
+            Grade::Correct
+        } else {
+            Grade::Incorrect {
+                feedback: "Incorrect answer".to_string(),
+            }
+        }
+    }
 }
 
 pub type AdapterHandle = Arc<dyn GameAdapter>;
@@ -0,0 +1,1045 @@
+use crate::adapter::{Grade, Prompt};
+use crate::game::{
+    MIN_EATABLE_SIZE, PlayerId, PlayerState, RoomPhase, RoomState, WrongAnswerPenalty,
+    apply_partial_credit, apply_round_win, apply_wrong_answer,
+};
+use crate::protocol::{ErrorCode, ServerMessage};
+use crate::win_condition::WinCondition;
+use std::time::Duration;
+
+/// Something that happened to a room. Events carry everything the room's
+/// rules need, clock readings included, so `reduce` is deterministic.
+#[derive(Debug, Clone)]
+pub enum RoomEvent {
+    /// A new player asks for a seat. Their id must be the room's
+    /// `next_player_id`.
+    PlayerJoined { player: PlayerState },
+    /// A receive-only connection starts watching. Its id must be the room's
+    /// `next_player_id`.
+    SpectatorJoined { spectator_id: PlayerId },
+    /// A player's typing changed. `text` is already normalized by the room's
+    /// adapter.
+    ProgressUpdated { player_id: PlayerId, text: String },
+    /// A player submitted an answer to the current prompt. `prompt_score` is
+    /// the adapter's growth for the prompt.
+    AttemptSubmitted {
+        player_id: PlayerId,
+        grade: Grade,
+        prompt_score: f32,
+        now_ms: u64,
+    },
+    /// A player's connection went away. They keep their seat until evicted.
+    PlayerLeft { player_id: PlayerId, now_ms: u64 },
+    /// A player came back with their rejoin token, which has been replaced by
+    /// the one hashed to `rejoin_token_hash`.
+    PlayerRejoined {
+        player_id: PlayerId,
+        rejoin_token_hash: String,
+        rejoin_token_expires_at_ms: u64,
+    },
+    /// Players who stayed disconnected longer than `grace_ms` lose their
+    /// seats.
+    PlayersExpired { now_ms: u64, grace_ms: u64 },
+    /// The host asks to leave the lobby and start playing.
+    MatchStarted { player_id: PlayerId },
+    /// The host asks for a new match in a finished room. Eliminated players
+    /// among `connected_player_ids` come back into play.
+    RematchRequested {
+        player_id: PlayerId,
+        connected_player_ids: Vec<PlayerId>,
+    },
+    /// The next prompt is dealt. The match clock starts with the first round.
+    RoundStarted {
+        prompt: Prompt,
+        now_ms: u64,
+        round_duration: Option<Duration>,
+    },
+    /// Round `round_id` ran out of time without a winner.
+    RoundExpired { round_id: u64, now_ms: u64 },
+    /// The match clock of a timed win condition ran out.
+    MatchDeadlinePassed { now_ms: u64 },
+    /// A pong from the player's connection took `latency_ms` to come back.
+    LatencyMeasured {
+        player_id: PlayerId,
+        latency_ms: u64,
+    },
+}
+
+/// Smallest change in a player's latency worth telling the room about.
+/// Smaller swings are ignored so steady pongs do not trigger broadcasts.
+pub const LATENCY_REPORT_STEP_MS: u64 = 25;
+
+/// What a room asks of the server after an event, in the order it should be
+/// carried out.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outbound {
+    /// A message for every connection in the room.
+    Broadcast(ServerMessage),
+    /// A message for one player's connection.
+    Send(PlayerId, ServerMessage),
+    /// The room's state changed and should be broadcast.
+    RoomState,
+    /// A player's progress changed; it goes out with the next progress tick.
+    Progress(PlayerId),
+    /// The round is over and the next prompt should be dealt.
+    NextRound,
+    /// Round `round_id` should expire at `ends_at_ms` unless it is won first.
+    RoundDeadline { round_id: u64, ends_at_ms: u64 },
+    /// The match clock runs out at this time.
+    MatchDeadline(u64),
+    /// These players lost their seats for good, so their rejoin tokens
+    /// should stop working.
+    Evict(Vec<PlayerId>),
+    /// Nobody is left in the room, so it should be closed.
+    CloseRoom,
+}
+
+/// Server settings the room's rules depend on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RoomRules {
+    /// Least a round win grows the winner by.
+    pub growth_per_round_win: f32,
+    pub wrong_answer_penalty: WrongAnswerPenalty,
+    pub elimination_size: f32,
+}
+
+/// Applies `event` to `room` and returns what should be sent as a result.
+/// A rejected event leaves the room untouched.
+pub fn reduce(
+    room: &mut RoomState,
+    event: RoomEvent,
+    rules: &RoomRules,
+) -> Result<Vec<Outbound>, ErrorCode> {
+    match event {
+        RoomEvent::PlayerJoined { player } => player_joined(room, player),
+        RoomEvent::SpectatorJoined { spectator_id } => {
+            room.next_player_id = room.next_player_id.max(spectator_id + 1);
+            let mut outbound = vec![
+                Outbound::Send(
+                    spectator_id,
+                    ServerMessage::SpectatorWelcome {
+                        spectator_id,
+                        room_code: room.room_code.clone(),
+                        game_key: room.game_key.clone(),
+                        min_eatable_size: MIN_EATABLE_SIZE,
+                    },
+                ),
+                Outbound::RoomState,
+            ];
+            if let Some(prompt_state) = prompt_state_message(room) {
+                outbound.push(Outbound::Send(spectator_id, prompt_state));
+            }
+            Ok(outbound)
+        }
+        RoomEvent::ProgressUpdated { player_id, text } => {
+            let Some(player) = room.players.get_mut(&player_id) else {
+                return Ok(Vec::new());
+            };
+            player.progress = text;
+            Ok(vec![Outbound::Progress(player_id)])
+        }
+        RoomEvent::AttemptSubmitted {
+            player_id,
+            grade,
+            prompt_score,
+            now_ms,
+        } => Ok(attempt_submitted(
+            room,
+            player_id,
+            grade,
+            prompt_score,
+            now_ms,
+            rules,
+        )),
+        RoomEvent::PlayerLeft { player_id, now_ms } => Ok(player_left(room, player_id, now_ms)),
+        RoomEvent::PlayerRejoined {
+            player_id,
+            rejoin_token_hash,
+            rejoin_token_expires_at_ms,
+        } => player_rejoined(
+            room,
+            player_id,
+            rejoin_token_hash,
+            rejoin_token_expires_at_ms,
+        ),
+        RoomEvent::PlayersExpired { now_ms, grace_ms } => {
+            Ok(players_expired(room, now_ms, grace_ms))
+        }
+        RoomEvent::MatchStarted { player_id } => {
+            if room.host_id != Some(player_id) {
+                return Err(ErrorCode::NotHost);
+            }
+            if room.phase != RoomPhase::Lobby {
+                return Err(ErrorCode::MatchAlreadyStarted);
+            }
+            room.phase = RoomPhase::Playing;
+            Ok(vec![Outbound::RoomState, Outbound::NextRound])
+        }
+        RoomEvent::RematchRequested {
+            player_id,
+            connected_player_ids,
+        } => {
+            if room.host_id != Some(player_id) {
+                return Err(ErrorCode::NotHost);
+            }
+            if room.phase != RoomPhase::Finished {
+                return Err(ErrorCode::MatchInProgress);
+            }
+            room.reset_for_rematch(|id| connected_player_ids.contains(&id));
+            Ok(vec![Outbound::RoomState, Outbound::NextRound])
+        }
+        RoomEvent::RoundStarted {
+            prompt,
+            now_ms,
+            round_duration,
+        } => Ok(round_started(room, prompt, now_ms, round_duration)),
+        RoomEvent::RoundExpired { round_id, now_ms } => Ok(round_expired(room, round_id, now_ms)),
+        RoomEvent::MatchDeadlinePassed { now_ms } => {
+            if room.match_winner.is_none() && room.update_match_winner(now_ms).is_some() {
+                Ok(vec![Outbound::RoomState])
+            } else {
+                Ok(Vec::new())
+            }
+        }
+        RoomEvent::LatencyMeasured {
+            player_id,
+            latency_ms,
+        } => {
+            let Some(player) = room.players.get_mut(&player_id) else {
+                return Err(ErrorCode::PlayerNotInRoom);
+            };
+            if player
+                .latency_ms
+                .is_some_and(|shown| shown.abs_diff(latency_ms) < LATENCY_REPORT_STEP_MS)
+            {
+                return Ok(Vec::new());
+            }
+            player.latency_ms = Some(latency_ms);
+            Ok(vec![Outbound::RoomState])
+        }
+    }
+}
+
+fn player_joined(room: &mut RoomState, player: PlayerState) -> Result<Vec<Outbound>, ErrorCode> {
+    if room.phase == RoomPhase::Playing {
+        return Err(ErrorCode::MatchInProgress);
+    }
+    if room.players.len() + room.eliminated.len() >= room.max_players {
+        return Err(ErrorCode::RoomFull);
+    }
+    if room.team_mode != player.team.is_some() {
+        return Err(ErrorCode::InvalidTeam);
+    }
+    if room.name_taken(&player.name) {
+        return Err(ErrorCode::NameTaken);
+    }
+    room.next_player_id = room.next_player_id.max(player.id + 1);
+    room.add_player(player);
+    room.ensure_host();
+    Ok(vec![Outbound::RoomState])
+}
+
+fn attempt_submitted(
+    room: &mut RoomState,
+    player_id: PlayerId,
+    grade: Grade,
+    prompt_score: f32,
+    now_ms: u64,
+    rules: &RoomRules,
+) -> Vec<Outbound> {
+    if room.match_winner.is_some() || room.prompt.is_none() {
+        return Vec::new();
+    }
+    let Some(player) = room.players.get(&player_id) else {
+        return Vec::new();
+    };
+    let growth = prompt_score.max(rules.growth_per_round_win);
+
+    if player.is_locked_out(now_ms) {
+        let message = ServerMessage::AttemptRejected {
+            room_code: room.room_code.clone(),
+            round_id: room.round_id,
+            feedback: "Locked out after too many wrong answers".to_string(),
+            growth_awarded: 0.0,
+            size_lost: 0.0,
+            locked_until_ms: player.locked_until_ms,
+            eliminated: false,
+        };
+        return vec![Outbound::Send(player_id, message)];
+    }
+
+    match grade {
+        Grade::Correct => round_won(room, player_id, growth, now_ms),
+        Grade::Partial { fraction, feedback } => {
+            let partial_growth = growth * fraction.clamp(0.0, 1.0);
+            let growth_awarded = apply_partial_credit(room, player_id, partial_growth, now_ms)
+                .map(|resolution| resolution.growth_awarded)
+                .unwrap_or(0.0);
+            let message = ServerMessage::AttemptRejected {
+                room_code: room.room_code.clone(),
+                round_id: room.round_id,
+                feedback,
+                growth_awarded,
+                size_lost: 0.0,
+                locked_until_ms: None,
+                eliminated: false,
+            };
+            let mut outbound = vec![Outbound::Send(player_id, message)];
+            if growth_awarded > 0.0 {
+                outbound.push(Outbound::RoomState);
+            }
+            outbound
+        }
+        Grade::Incorrect { feedback } => {
+            let Some(penalty) = apply_wrong_answer(
+                room,
+                player_id,
+                rules.wrong_answer_penalty,
+                rules.elimination_size,
+                now_ms,
+            ) else {
+                return Vec::new();
+            };
+            let message = ServerMessage::AttemptRejected {
+                room_code: room.room_code.clone(),
+                round_id: room.round_id,
+                feedback,
+                growth_awarded: 0.0,
+                size_lost: penalty.size_lost,
+                locked_until_ms: penalty.locked_until_ms,
+                eliminated: penalty.eliminated,
+            };
+            vec![Outbound::Send(player_id, message), Outbound::RoomState]
+        }
+    }
+}
+
+fn round_won(room: &mut RoomState, player_id: PlayerId, growth: f32, now_ms: u64) -> Vec<Outbound> {
+    let Some(resolution) = apply_round_win(room, player_id, growth, MIN_EATABLE_SIZE, now_ms)
+    else {
+        return Vec::new();
+    };
+    let mut outbound = vec![
+        Outbound::Broadcast(ServerMessage::RoundResult {
+            room_code: room.room_code.clone(),
+            round_id: room.round_id,
+            winner_player_id: resolution.round_winner,
+            growth_awarded: growth,
+            consumed_player_ids: resolution.consumed_player_ids,
+            match_winner: resolution.match_winner,
+        }),
+        Outbound::RoomState,
+    ];
+    if resolution.match_winner.is_none() {
+        outbound.push(Outbound::NextRound);
+    }
+    outbound
+}
+
+fn player_left(room: &mut RoomState, player_id: PlayerId, now_ms: u64) -> Vec<Outbound> {
+    if let Some(player) = room.players.get_mut(&player_id) {
+        player.connected = false;
+        player.latency_ms = None;
+        player.disconnected_at_ms = Some(now_ms);
+    }
+    if let Some(eliminated) = room.eliminated.get_mut(&player_id) {
+        eliminated.player.connected = false;
+        eliminated.player.disconnected_at_ms = Some(now_ms);
+    }
+    // The room stays open even once nobody is connected, so players can
+    // rejoin until the grace window evicts them.
+    room.ensure_host();
+    vec![Outbound::RoomState]
+}
+
+fn player_rejoined(
+    room: &mut RoomState,
+    player_id: PlayerId,
+    rejoin_token_hash: String,
+    rejoin_token_expires_at_ms: u64,
+) -> Result<Vec<Outbound>, ErrorCode> {
+    let player = match room.players.get_mut(&player_id) {
+        Some(player) => player,
+        None => match room.eliminated.get_mut(&player_id) {
+            Some(eliminated) => &mut eliminated.player,
+            None => return Err(ErrorCode::PlayerNotInRoom),
+        },
+    };
+    player.connected = true;
+    player.disconnected_at_ms = None;
+    player.rejoin_token_hash = rejoin_token_hash;
+    player.rejoin_token_expires_at_ms = rejoin_token_expires_at_ms;
+    room.ensure_host();
+    Ok(vec![Outbound::RoomState])
+}
+
+fn players_expired(room: &mut RoomState, now_ms: u64, grace_ms: u64) -> Vec<Outbound> {
+    let evicted = room.expired_players(now_ms, grace_ms);
+    if evicted.is_empty() {
+        return Vec::new();
+    }
+    for player_id in &evicted {
+        room.remove_player(*player_id);
+    }
+    // Eliminated players still hold their seats as spectators, so the room
+    // stays open for them until they are gone too.
+    if room.players.is_empty() && room.eliminated.is_empty() {
+        return vec![Outbound::Evict(evicted), Outbound::CloseRoom];
+    }
+    if room.phase == RoomPhase::Playing {
+        room.update_match_winner(now_ms);
+    }
+    let mut outbound = vec![Outbound::Evict(evicted.clone())];
+    outbound.extend(evicted.into_iter().map(|player_id| {
+        Outbound::Broadcast(ServerMessage::PlayerLeft {
+            room_code: room.room_code.clone(),
+            player_id,
+        })
+    }));
+    outbound.push(Outbound::RoomState);
+    outbound
+}
+
+fn round_started(
+    room: &mut RoomState,
+    prompt: Prompt,
+    now_ms: u64,
+    round_duration: Option<Duration>,
+) -> Vec<Outbound> {
+    if room.phase != RoomPhase::Playing || room.players.is_empty() {
+        return Vec::new();
+    }
+    let mut outbound = Vec::new();
+    if room.match_started_at_ms.is_none() {
+        room.match_started_at_ms = Some(now_ms);
+        if let Some(deadline_ms) = room.win_condition.deadline_ms(room) {
+            outbound.push(Outbound::MatchDeadline(deadline_ms));
+        }
+    }
+    room.round_id += 1;
+    for player in room.players.values_mut() {
+        player.progress.clear();
+        player.partial_credit_claimed = false;
+        player.wrong_attempts = 0;
+    }
+    room.round_ends_at_ms = round_duration.map(|duration| {
+        now_ms.saturating_add(u64::try_from(duration.as_millis()).unwrap_or(u64::MAX))
+    });
+    room.prompt = Some(prompt);
+
+    if let Some(ends_at_ms) = room.round_ends_at_ms {
+        outbound.push(Outbound::RoundDeadline {
+            round_id: room.round_id,
+            ends_at_ms,
+        });
+    }
+    outbound.extend(prompt_state_message(room).map(Outbound::Broadcast));
+    outbound
+}
+
+fn round_expired(room: &mut RoomState, round_id: u64, now_ms: u64) -> Vec<Outbound> {
+    if room.round_id != round_id || room.match_winner.is_some() {
+        return Vec::new();
+    }
+    let Some(prompt) = room.prompt.take() else {
+        return Vec::new();
+    };
+    room.round_ends_at_ms = None;
+    let match_over = room.update_match_winner(now_ms).is_some();
+    vec![
+        Outbound::Broadcast(ServerMessage::RoundExpired {
+            room_code: room.room_code.clone(),
+            round_id,
+            answer: prompt.answer_text(),
+        }),
+        if match_over {
+            Outbound::RoomState
+        } else {
+            Outbound::NextRound
+        },
+    ]
+}
+
+/// The current prompt as players see it, if a round is under way.
+pub fn prompt_state_message(room: &RoomState) -> Option<ServerMessage> {
+    room.prompt
+        .as_ref()
+        .map(|prompt| ServerMessage::PromptState {
+            room_code: room.room_code.clone(),
+            round_id: room.round_id,
+            prompt: prompt.display.clone(),
+            prompt_metadata: prompt.metadata.clone(),
+            round_ends_at_ms: room.round_ends_at_ms,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapter::Prompt;
+    use crate::game::DEFAULT_START_SIZE;
+    use crate::win_condition::WinConditionKind;
+
+    const RULES: RoomRules = RoomRules {
+        growth_per_round_win: 4.0,
+        wrong_answer_penalty: WrongAnswerPenalty::FixedShrink(3.0),
+        elimination_size: 5.0,
+    };
+
+    fn player(id: PlayerId, name: &str) -> PlayerState {
+        PlayerState::new(id, name.to_string(), "#ffffff".to_string(), String::new())
+    }
+
+    fn lobby(max_players: usize) -> RoomState {
+        let mut room = RoomState::new(
+            "ABCD".to_string(),
+            "keyboarding".to_string(),
+            WinConditionKind::Dominance,
+        );
+        room.max_players = max_players;
+        room
+    }
+
+    /// A room mid-round with players of the given sizes, ids from 1.
+    fn playing(sizes: &[f32]) -> RoomState {
+        let mut room = lobby(8);
+        for size in sizes {
+            let id = room.next_player_id;
+            let joined = PlayerState {
+                size: *size,
+                ..player(id, &format!("p{id}"))
+            };
+            reduce(
+                &mut room,
+                RoomEvent::PlayerJoined { player: joined },
+                &RULES,
+            )
+            .expect("player joins");
+        }
+        room.phase = RoomPhase::Playing;
+        room.prompt = Some(Prompt::new("abc", "abc"));
+        room.round_id = 1;
+        room
+    }
+
+    fn submit(player_id: PlayerId, grade: Grade, now_ms: u64) -> RoomEvent {
+        RoomEvent::AttemptSubmitted {
+            player_id,
+            grade,
+            prompt_score: 0.0,
+            now_ms,
+        }
+    }
+
+    #[test]
+    fn rejected_joins_leave_the_room_untouched() {
+        let mut room = lobby(2);
+        let first = room.next_player_id;
+        assert_eq!(
+            reduce(
+                &mut room,
+                RoomEvent::PlayerJoined {
+                    player: player(first, "Alice"),
+                },
+                &RULES,
+            ),
+            Ok(vec![Outbound::RoomState])
+        );
+        assert_eq!(room.host_id, Some(first));
+
+        let second = room.next_player_id;
+        assert_eq!(second, first + 1);
+        let rejected = reduce(
+            &mut room,
+            RoomEvent::PlayerJoined {
+                player: player(second, " alice "),
+            },
+            &RULES,
+        );
+        assert_eq!(rejected, Err(ErrorCode::NameTaken));
+        assert_eq!(room.next_player_id, second);
+        assert_eq!(room.players.len(), 1);
+
+        let team_player = PlayerState {
+            team: Some("red".to_string()),
+            ..player(second, "Bob")
+        };
+        let rejected = reduce(
+            &mut room,
+            RoomEvent::PlayerJoined {
+                player: team_player,
+            },
+            &RULES,
+        );
+        assert_eq!(rejected, Err(ErrorCode::InvalidTeam));
+
+        room.phase = RoomPhase::Playing;
+        let rejected = reduce(
+            &mut room,
+            RoomEvent::PlayerJoined {
+                player: player(second, "Bob"),
+            },
+            &RULES,
+        );
+        assert_eq!(rejected, Err(ErrorCode::MatchInProgress));
+    }
+
+    #[test]
+    fn progress_is_queued_for_seated_players_only() {
+        let mut room = playing(&[10.0, 10.0]);
+        let update = |player_id| RoomEvent::ProgressUpdated {
+            player_id,
+            text: "ab".to_string(),
+        };
+        assert_eq!(
+            reduce(&mut room, update(1), &RULES),
+            Ok(vec![Outbound::Progress(1)])
+        );
+        assert_eq!(room.players[&1].progress, "ab");
+        assert_eq!(reduce(&mut room, update(9), &RULES), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn a_correct_attempt_wins_the_round_and_consumes_smaller_players() {
+        let mut room = playing(&[20.0, 9.0, 30.0]);
+        let outbound = reduce(&mut room, submit(1, Grade::Correct, 0), &RULES);
+        assert_eq!(
+            outbound,
+            Ok(vec![
+                Outbound::Broadcast(ServerMessage::RoundResult {
+                    room_code: "ABCD".to_string(),
+                    round_id: 1,
+                    winner_player_id: 1,
+                    growth_awarded: 4.0,
+                    consumed_player_ids: vec![2],
+                    match_winner: None,
+                }),
+                Outbound::RoomState,
+                Outbound::NextRound,
+            ])
+        );
+        assert_eq!(room.players[&1].size, 24.0);
+        assert_eq!(room.eliminated[&2].eaten_by, Some(1));
+    }
+
+    #[test]
+    fn winning_the_match_ends_the_rounds() {
+        let mut room = playing(&[10.0, 6.0]);
+        let outbound = reduce(&mut room, submit(1, Grade::Correct, 0), &RULES).expect("round won");
+        assert_eq!(outbound.last(), Some(&Outbound::RoomState));
+        assert_eq!(room.match_winner, Some(1));
+        assert_eq!(
+            reduce(&mut room, submit(1, Grade::Correct, 0), &RULES),
+            Ok(Vec::new())
+        );
+    }
+
+    #[test]
+    fn wrong_answers_are_reported_to_the_submitter_and_can_eliminate() {
+        let mut room = playing(&[7.0, 10.0]);
+        let outbound = reduce(
+            &mut room,
+            submit(
+                1,
+                Grade::Incorrect {
+                    feedback: "nope".to_string(),
+                },
+                0,
+            ),
+            &RULES,
+        );
+        assert_eq!(
+            outbound,
+            Ok(vec![
+                Outbound::Send(
+                    1,
+                    ServerMessage::AttemptRejected {
+                        room_code: "ABCD".to_string(),
+                        round_id: 1,
+                        feedback: "nope".to_string(),
+                        growth_awarded: 0.0,
+                        size_lost: 3.0,
+                        locked_until_ms: None,
+                        eliminated: true,
+                    }
+                ),
+                Outbound::RoomState,
+            ])
+        );
+        assert!(room.eliminated.contains_key(&1));
+    }
+
+    #[test]
+    fn locked_out_players_are_turned_away_without_grading() {
+        let mut room = playing(&[10.0, 10.0]);
+        room.players.get_mut(&1).expect("player").locked_until_ms = Some(1_000);
+        let outbound = reduce(&mut room, submit(1, Grade::Correct, 500), &RULES).expect("ok");
+        assert!(matches!(
+            outbound.as_slice(),
+            [Outbound::Send(
+                1,
+                ServerMessage::AttemptRejected {
+                    locked_until_ms: Some(1_000),
+                    ..
+                }
+            )]
+        ));
+        assert_eq!(room.players[&1].size, DEFAULT_START_SIZE);
+
+        let outbound = reduce(&mut room, submit(1, Grade::Correct, 1_000), &RULES).expect("ok");
+        assert!(outbound.contains(&Outbound::NextRound));
+    }
+
+    #[test]
+    fn the_room_stays_open_after_every_player_has_left() {
+        let mut room = playing(&[10.0, 10.0]);
+        assert_eq!(room.host_id, Some(1));
+        let left = |player_id, now_ms| RoomEvent::PlayerLeft { player_id, now_ms };
+
+        assert_eq!(
+            reduce(&mut room, left(1, 100), &RULES),
+            Ok(vec![Outbound::RoomState])
+        );
+        assert_eq!(room.players[&1].disconnected_at_ms, Some(100));
+        assert_eq!(room.host_id, Some(2));
+        assert_eq!(
+            reduce(&mut room, left(2, 200), &RULES),
+            Ok(vec![Outbound::RoomState])
+        );
+        assert_eq!(room.players.len(), 2);
+        assert!(room.players.values().all(|player| !player.connected));
+    }
+
+    #[test]
+    fn rejoining_reconnects_the_seat_with_a_fresh_token() {
+        let mut room = playing(&[10.0, 10.0]);
+        reduce(
+            &mut room,
+            RoomEvent::PlayerLeft {
+                player_id: 2,
+                now_ms: 100,
+            },
+            &RULES,
+        )
+        .expect("left");
+        let rejoin = |player_id| RoomEvent::PlayerRejoined {
+            player_id,
+            rejoin_token_hash: "fresh".to_string(),
+            rejoin_token_expires_at_ms: 5_000,
+        };
+
+        assert_eq!(
+            reduce(&mut room, rejoin(2), &RULES),
+            Ok(vec![Outbound::RoomState])
+        );
+        let player = &room.players[&2];
+        assert!(player.connected);
+        assert_eq!(player.disconnected_at_ms, None);
+        assert_eq!(player.rejoin_token_hash, "fresh");
+        assert_eq!(player.rejoin_token_expires_at_ms, 5_000);
+        assert_eq!(
+            reduce(&mut room, rejoin(9), &RULES),
+            Err(ErrorCode::PlayerNotInRoom)
+        );
+    }
+
+    #[test]
+    fn players_gone_past_the_grace_window_lose_their_seats() {
+        let mut room = playing(&[10.0, 10.0, 10.0]);
+        for (player_id, now_ms) in [(2, 1_000), (3, 50_000)] {
+            reduce(
+                &mut room,
+                RoomEvent::PlayerLeft { player_id, now_ms },
+                &RULES,
+            )
+            .expect("left");
+        }
+        let expire = |now_ms| RoomEvent::PlayersExpired {
+            now_ms,
+            grace_ms: 60_000,
+        };
+
+        assert_eq!(reduce(&mut room, expire(60_999), &RULES), Ok(Vec::new()));
+        assert_eq!(
+            reduce(&mut room, expire(61_000), &RULES),
+            Ok(vec![
+                Outbound::Evict(vec![2]),
+                Outbound::Broadcast(ServerMessage::PlayerLeft {
+                    room_code: "ABCD".to_string(),
+                    player_id: 2,
+                }),
+                Outbound::RoomState,
+            ])
+        );
+        assert!(!room.players.contains_key(&2));
+
+        reduce(
+            &mut room,
+            RoomEvent::PlayerLeft {
+                player_id: 1,
+                now_ms: 50_000,
+            },
+            &RULES,
+        )
+        .expect("left");
+        assert_eq!(
+            reduce(&mut room, expire(110_000), &RULES),
+            Ok(vec![Outbound::Evict(vec![1, 3]), Outbound::CloseRoom])
+        );
+    }
+
+    #[test]
+    fn the_room_stays_open_while_eliminated_players_watch() {
+        let mut room = playing(&[10.0, 10.0]);
+        room.eliminate_player(2, Some(1));
+        let leave = |player_id, now_ms| RoomEvent::PlayerLeft { player_id, now_ms };
+        let expire = |now_ms| RoomEvent::PlayersExpired {
+            now_ms,
+            grace_ms: 60_000,
+        };
+
+        reduce(&mut room, leave(1, 0), &RULES).expect("left");
+        let outbound = reduce(&mut room, expire(60_000), &RULES).expect("expired");
+        assert_eq!(outbound.first(), Some(&Outbound::Evict(vec![1])));
+        assert!(!outbound.contains(&Outbound::CloseRoom));
+        assert!(room.players.is_empty());
+        assert!(room.eliminated[&2].player.connected);
+
+        reduce(&mut room, leave(2, 70_000), &RULES).expect("left");
+        assert_eq!(
+            reduce(&mut room, expire(130_000), &RULES),
+            Ok(vec![Outbound::Evict(vec![2]), Outbound::CloseRoom])
+        );
+    }
+
+    #[test]
+    fn only_the_host_starts_and_restarts_matches() {
+        let mut room = playing(&[10.0, 10.0]);
+        room.phase = RoomPhase::Lobby;
+        let start = |player_id| RoomEvent::MatchStarted { player_id };
+        assert_eq!(reduce(&mut room, start(2), &RULES), Err(ErrorCode::NotHost));
+        assert_eq!(
+            reduce(&mut room, start(1), &RULES),
+            Ok(vec![Outbound::RoomState, Outbound::NextRound])
+        );
+        assert_eq!(room.phase, RoomPhase::Playing);
+        assert_eq!(
+            reduce(&mut room, start(1), &RULES),
+            Err(ErrorCode::MatchAlreadyStarted)
+        );
+
+        let rematch = |player_id| RoomEvent::RematchRequested {
+            player_id,
+            connected_player_ids: vec![1, 2],
+        };
+        assert_eq!(
+            reduce(&mut room, rematch(1), &RULES),
+            Err(ErrorCode::MatchInProgress)
+        );
+        room.eliminate_player(2, Some(1));
+        room.phase = RoomPhase::Finished;
+        room.match_winner = Some(1);
+        assert_eq!(
+            reduce(&mut room, rematch(2), &RULES),
+            Err(ErrorCode::NotHost)
+        );
+        assert_eq!(
+            reduce(&mut room, rematch(1), &RULES),
+            Ok(vec![Outbound::RoomState, Outbound::NextRound])
+        );
+        assert!(room.players.contains_key(&2));
+        assert_eq!(room.match_winner, None);
+        assert_eq!(room.match_number, 2);
+    }
+
+    #[test]
+    fn starting_a_round_deals_the_prompt_and_arms_its_clocks() {
+        let mut room = playing(&[10.0, 10.0]);
+        room.win_condition = WinConditionKind::Timed { duration_secs: 60 };
+        {
+            let player = room.players.get_mut(&1).expect("player");
+            player.progress = "ab".to_string();
+            player.partial_credit_claimed = true;
+            player.wrong_attempts = 2;
+        }
+        let start = |now_ms, round_duration| RoomEvent::RoundStarted {
+            prompt: Prompt::new("xyz", "xyz"),
+            now_ms,
+            round_duration,
+        };
+
+        let outbound = reduce(
+            &mut room,
+            start(1_000, Some(Duration::from_secs(30))),
+            &RULES,
+        );
+        assert_eq!(
+            outbound,
+            Ok(vec![
+                Outbound::MatchDeadline(61_000),
+                Outbound::RoundDeadline {
+                    round_id: 2,
+                    ends_at_ms: 31_000,
+                },
+                Outbound::Broadcast(ServerMessage::PromptState {
+                    room_code: "ABCD".to_string(),
+                    round_id: 2,
+                    prompt: "xyz".to_string(),
+                    prompt_metadata: Default::default(),
+                    round_ends_at_ms: Some(31_000),
+                }),
+            ])
+        );
+        let player = &room.players[&1];
+        assert_eq!(player.progress, "");
+        assert!(!player.partial_credit_claimed);
+        assert_eq!(player.wrong_attempts, 0);
+
+        // The match clock only starts once, and a huge round never wraps.
+        let outbound = reduce(&mut room, start(2_000, Some(Duration::MAX)), &RULES).expect("ok");
+        assert!(matches!(
+            outbound.as_slice(),
+            [
+                Outbound::RoundDeadline {
+                    round_id: 3,
+                    ends_at_ms: u64::MAX,
+                },
+                Outbound::Broadcast(_),
+            ]
+        ));
+
+        room.phase = RoomPhase::Finished;
+        assert_eq!(
+            reduce(&mut room, start(3_000, None), &RULES),
+            Ok(Vec::new())
+        );
+        assert_eq!(room.round_id, 3);
+    }
+
+    #[test]
+    fn spectators_get_a_welcome_and_the_current_prompt() {
+        let mut room = playing(&[10.0, 10.0]);
+        let spectator_id = room.next_player_id;
+        let outbound = reduce(
+            &mut room,
+            RoomEvent::SpectatorJoined { spectator_id },
+            &RULES,
+        )
+        .expect("spectating");
+
+        assert!(matches!(
+            outbound.as_slice(),
+            [
+                Outbound::Send(id, ServerMessage::SpectatorWelcome { .. }),
+                Outbound::RoomState,
+                Outbound::Send(_, ServerMessage::PromptState { .. }),
+            ] if *id == spectator_id
+        ));
+        assert_eq!(room.next_player_id, spectator_id + 1);
+        assert!(!room.players.contains_key(&spectator_id));
+    }
+
+    #[test]
+    fn an_expired_round_reveals_the_answer_once() {
+        let mut room = playing(&[10.0, 10.0]);
+        let expire = |round_id| RoomEvent::RoundExpired {
+            round_id,
+            now_ms: 0,
+        };
+
+        assert_eq!(reduce(&mut room, expire(7), &RULES), Ok(Vec::new()));
+        assert_eq!(
+            reduce(&mut room, expire(1), &RULES),
+            Ok(vec![
+                Outbound::Broadcast(ServerMessage::RoundExpired {
+                    room_code: "ABCD".to_string(),
+                    round_id: 1,
+                    answer: "abc".to_string(),
+                }),
+                Outbound::NextRound,
+            ])
+        );
+        assert_eq!(room.prompt, None);
+        assert_eq!(reduce(&mut room, expire(1), &RULES), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn the_match_clock_settles_timed_matches() {
+        let mut room = playing(&[20.0, 10.0]);
+        room.win_condition = WinConditionKind::Timed { duration_secs: 60 };
+        room.match_started_at_ms = Some(0);
+        let deadline = |now_ms| RoomEvent::MatchDeadlinePassed { now_ms };
+
+        assert_eq!(reduce(&mut room, deadline(59_999), &RULES), Ok(Vec::new()));
+        assert_eq!(
+            reduce(&mut room, deadline(60_000), &RULES),
+            Ok(vec![Outbound::RoomState])
+        );
+        assert_eq!(room.match_winner, Some(1));
+        assert_eq!(reduce(&mut room, deadline(61_000), &RULES), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn latency_is_only_reported_when_it_moves_by_a_step() {
+        let mut room = playing(&[10.0, 10.0]);
+        let measured = |player_id, latency_ms| RoomEvent::LatencyMeasured {
+            player_id,
+            latency_ms,
+        };
+
+        assert_eq!(
+            reduce(&mut room, measured(1, 42), &RULES),
+            Ok(vec![Outbound::RoomState])
+        );
+        assert_eq!(reduce(&mut room, measured(1, 50), &RULES), Ok(Vec::new()));
+        assert_eq!(room.players[&1].latency_ms, Some(42));
+        assert_eq!(
+            reduce(&mut room, measured(1, 90), &RULES),
+            Ok(vec![Outbound::RoomState])
+        );
+        assert_eq!(
+            reduce(&mut room, measured(9, 5), &RULES),
+            Err(ErrorCode::PlayerNotInRoom)
+        );
+    }
+
+    #[test]
+    fn replaying_an_event_log_gives_the_same_room_and_messages() {
+        let log = [
+            RoomEvent::ProgressUpdated {
+                player_id: 2,
+                text: "a".to_string(),
+            },
+            submit(
+                2,
+                Grade::Partial {
+                    fraction: 0.5,
+                    feedback: "close".to_string(),
+                },
+                10,
+            ),
+            submit(
+                3,
+                Grade::Incorrect {
+                    feedback: "nope".to_string(),
+                },
+                20,
+            ),
+            submit(1, Grade::Correct, 30),
+            RoomEvent::PlayerLeft {
+                player_id: 2,
+                now_ms: 40,
+            },
+        ];
+        let replay = || {
+            let mut room = playing(&[20.0, 10.0, 12.0]);
+            let outbound: Vec<_> = log
+                .iter()
+                .cloned()
+                .map(|event| reduce(&mut room, event, &RULES))
+                .collect();
+            (room.to_snapshot(), outbound)
+        };
+        assert_eq!(replay(), replay());
+    }
+}
@@ -1,12 +1,19 @@
-use serde::Serialize;
-use std::collections::HashMap;
+use crate::adapter::Prompt;
+use crate::win_condition::{WinCondition, WinConditionKind};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
 
 pub const DEFAULT_START_SIZE: f32 = 10.0;
 pub const MIN_EATABLE_SIZE: f32 = 18.0;
+pub const MIN_PLAYER_SIZE: f32 = 5.0;
+pub const MAX_TEAM_NAME_CHARS: usize = 24;
+pub const DEFAULT_MAX_PLAYERS: usize = 8;
 
 pub type PlayerId = u64;
+pub type TeamId = String;
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PlayerSnapshot {
     pub id: PlayerId,
@@ -15,9 +22,13 @@ pub struct PlayerSnapshot {
     pub color: String,
     pub connected: bool,
     pub progress: String,
+    pub wrong_attempts: u32,
+    pub locked_until_ms: Option<u64>,
+    pub team: Option<TeamId>,
+    pub latency_ms: Option<u64>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PlayerState {
     pub id: PlayerId,
     pub name: String,
@@ -25,10 +36,48 @@ pub struct PlayerState {
     pub color: String,
     pub connected: bool,
     pub progress: String,
-    pub rejoin_token: String,
+    /// SHA-256 of the player's current rejoin token. The token itself is
+    /// only ever sent to the player.
+    #[serde(default)]
+    pub rejoin_token_hash: String,
+    /// When the current rejoin token stops being accepted.
+    #[serde(default)]
+    pub rejoin_token_expires_at_ms: u64,
+    pub partial_credit_claimed: bool,
+    pub wrong_attempts: u32,
+    pub locked_until_ms: Option<u64>,
+    /// The team whose blob this player shares, in team rooms. The player's
+    /// `size` then mirrors the team's mass.
+    #[serde(default)]
+    pub team: Option<TeamId>,
+    /// Round trip of the last answered WebSocket ping, while connected.
+    #[serde(skip)]
+    pub latency_ms: Option<u64>,
+    /// When the player lost their connection. Cleared when they rejoin.
+    #[serde(default)]
+    pub disconnected_at_ms: Option<u64>,
 }
 
 impl PlayerState {
+    pub fn new(id: PlayerId, name: String, color: String, rejoin_token_hash: String) -> Self {
+        Self {
+            id,
+            name,
+            size: DEFAULT_START_SIZE,
+            color,
+            connected: true,
+            progress: String::new(),
+            rejoin_token_hash,
+            rejoin_token_expires_at_ms: 0,
+            partial_credit_claimed: false,
+            wrong_attempts: 0,
+            locked_until_ms: None,
+            team: None,
+            latency_ms: None,
+            disconnected_at_ms: None,
+        }
+    }
+
     pub fn to_snapshot(&self) -> PlayerSnapshot {
         PlayerSnapshot {
             id: self.id,
@@ -37,32 +86,567 @@ impl PlayerState {
             color: self.color.clone(),
             connected: self.connected,
             progress: self.progress.clone(),
+            wrong_attempts: self.wrong_attempts,
+            locked_until_ms: self.locked_until_ms,
+            team: self.team.clone(),
+            latency_ms: self.latency_ms,
         }
     }
+
+    pub fn is_locked_out(&self, now_ms: u64) -> bool {
+        self.locked_until_ms.is_some_and(|until| now_ms < until)
+    }
 }
 
-#[derive(Debug, Clone, Serialize)]
+/// A player who has been consumed or eliminated. They stay connected as a
+/// spectator until the match ends.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EliminatedPlayer {
+    pub player: PlayerState,
+    pub eliminated_in_round: u64,
+    /// The player who ate them, or `None` if a penalty shrank them out.
+    pub eaten_by: Option<PlayerId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EliminatedSnapshot {
+    pub id: PlayerId,
+    pub name: String,
+    pub color: String,
+    pub connected: bool,
+    pub eliminated_in_round: u64,
+    pub eaten_by: Option<PlayerId>,
+}
+
+impl EliminatedPlayer {
+    pub fn to_snapshot(&self) -> EliminatedSnapshot {
+        EliminatedSnapshot {
+            id: self.player.id,
+            name: self.player.name.clone(),
+            color: self.player.color.clone(),
+            connected: self.player.connected,
+            eliminated_in_round: self.eliminated_in_round,
+            eaten_by: self.eaten_by,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TeamSnapshot {
+    pub name: TeamId,
+    pub size: f32,
+    pub member_ids: Vec<PlayerId>,
+}
+
+/// Lifecycle of a room: players gather in the lobby until the host starts
+/// the match, and the room is finished once a match winner is declared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RoomPhase {
+    #[default]
+    Lobby,
+    Playing,
+    Finished,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RoomSnapshot {
     pub room_code: String,
     pub players: Vec<PlayerSnapshot>,
+    pub eliminated: Vec<EliminatedSnapshot>,
     pub prompt: String,
+    pub prompt_metadata: BTreeMap<String, String>,
     pub round_id: u64,
+    pub round_ends_at_ms: Option<u64>,
     pub match_winner: Option<PlayerId>,
+    pub win_condition: WinConditionKind,
+    pub match_ends_at_ms: Option<u64>,
+    pub phase: RoomPhase,
+    pub host_id: Option<PlayerId>,
+    pub match_number: u64,
+    pub team_mode: bool,
+    pub teams: Vec<TeamSnapshot>,
+    pub winning_team: Option<TeamId>,
+    pub max_players: usize,
+}
+
+impl RoomSnapshot {
+    /// Player-level changes from `self` to `newer`: patches for new or
+    /// changed players and the ids of players who left. Returns `None` when
+    /// anything besides the player list changed, in which case a full
+    /// snapshot is needed.
+    pub fn player_changes(
+        &self,
+        newer: &RoomSnapshot,
+    ) -> Option<(Vec<PlayerPatch>, Vec<PlayerId>)> {
+        let without_players = |snapshot: &RoomSnapshot| RoomSnapshot {
+            players: Vec::new(),
+            ..snapshot.clone()
+        };
+        if without_players(self) != without_players(newer) {
+            return None;
+        }
+
+        let patches = newer
+            .players
+            .iter()
+            .filter_map(
+                |player| match self.players.iter().find(|old| old.id == player.id) {
+                    Some(old) => PlayerPatch::between(old, player),
+                    None => Some(PlayerPatch::new_player(player)),
+                },
+            )
+            .collect();
+        let removed = self
+            .players
+            .iter()
+            .filter(|old| !newer.players.iter().any(|player| player.id == old.id))
+            .map(|old| old.id)
+            .collect();
+        Some((patches, removed))
+    }
+}
+
+/// Changed fields of one player. Unchanged fields are left out, and a player
+/// new to the room carries every field.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerPatch {
+    pub id: PlayerId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connected: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wrong_attempts: Option<u32>,
+    /// `Some(None)` clears an expired lockout.
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub locked_until_ms: Option<Option<u64>>,
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub team: Option<Option<TeamId>>,
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub latency_ms: Option<Option<u64>>,
+}
+
+impl PlayerPatch {
+    fn new_player(player: &PlayerSnapshot) -> Self {
+        Self {
+            id: player.id,
+            name: Some(player.name.clone()),
+            size: Some(player.size),
+            color: Some(player.color.clone()),
+            connected: Some(player.connected),
+            progress: Some(player.progress.clone()),
+            wrong_attempts: Some(player.wrong_attempts),
+            locked_until_ms: Some(player.locked_until_ms),
+            team: Some(player.team.clone()),
+            latency_ms: Some(player.latency_ms),
+        }
+    }
+
+    fn between(old: &PlayerSnapshot, new: &PlayerSnapshot) -> Option<Self> {
+        fn changed<T: PartialEq + Clone>(old: &T, new: &T) -> Option<T> {
+            (old != new).then(|| new.clone())
+        }
+        let patch = Self {
+            id: new.id,
+            name: changed(&old.name, &new.name),
+            size: changed(&old.size, &new.size),
+            color: changed(&old.color, &new.color),
+            connected: changed(&old.connected, &new.connected),
+            progress: changed(&old.progress, &new.progress),
+            wrong_attempts: changed(&old.wrong_attempts, &new.wrong_attempts),
+            locked_until_ms: changed(&old.locked_until_ms, &new.locked_until_ms),
+            team: changed(&old.team, &new.team),
+            latency_ms: changed(&old.latency_ms, &new.latency_ms),
+        };
+        (patch
+            != Self {
+                id: new.id,
+                ..Self::default()
+            })
+        .then_some(patch)
+    }
+
+    /// Applies the patch to `player`, or builds the player when it is new.
+    pub fn apply(&self, player: Option<&PlayerSnapshot>) -> Option<PlayerSnapshot> {
+        let mut player = match player {
+            Some(player) => player.clone(),
+            None => PlayerSnapshot {
+                id: self.id,
+                name: self.name.clone()?,
+                size: self.size?,
+                color: self.color.clone()?,
+                connected: self.connected?,
+                progress: self.progress.clone()?,
+                wrong_attempts: self.wrong_attempts?,
+                locked_until_ms: self.locked_until_ms?,
+                team: self.team.clone()?,
+                latency_ms: self.latency_ms?,
+            },
+        };
+        if let Some(name) = &self.name {
+            player.name = name.clone();
+        }
+        if let Some(size) = self.size {
+            player.size = size;
+        }
+        if let Some(color) = &self.color {
+            player.color = color.clone();
+        }
+        if let Some(connected) = self.connected {
+            player.connected = connected;
+        }
+        if let Some(progress) = &self.progress {
+            player.progress = progress.clone();
+        }
+        if let Some(wrong_attempts) = self.wrong_attempts {
+            player.wrong_attempts = wrong_attempts;
+        }
+        if let Some(locked_until_ms) = self.locked_until_ms {
+            player.locked_until_ms = locked_until_ms;
+        }
+        if let Some(team) = &self.team {
+            player.team = team.clone();
+        }
+        if let Some(latency_ms) = self.latency_ms {
+            player.latency_ms = latency_ms;
+        }
+        Some(player)
+    }
 }
 
-#[derive(Debug, Clone)]
+/// Reads a field that is present, even as `null`, as `Some`, so a missing
+/// field and an explicit `null` can be told apart.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// Saved by the room store, so fields added since rooms were first persisted
+/// carry `#[serde(default)]` and older saves still load.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RoomState {
     pub room_code: String,
     pub game_key: String,
     pub players: HashMap<PlayerId, PlayerState>,
-    pub prompt: String,
+    pub prompt: Option<Prompt>,
     pub round_id: u64,
+    pub round_ends_at_ms: Option<u64>,
     pub match_winner: Option<PlayerId>,
     pub next_player_id: u64,
+    pub win_condition: WinConditionKind,
+    pub match_started_at_ms: Option<u64>,
+    #[serde(default)]
+    pub phase: RoomPhase,
+    #[serde(default)]
+    pub host_id: Option<PlayerId>,
+    /// Players consumed or eliminated during the current match. They watch
+    /// as spectators and are brought back by a rematch.
+    #[serde(default)]
+    pub eliminated: HashMap<PlayerId, EliminatedPlayer>,
+    #[serde(default = "first_match_number")]
+    pub match_number: u64,
+    /// `round_id` of the last round played before the current match began.
+    #[serde(default)]
+    pub match_start_round_id: u64,
+    /// Team rooms pool each team's mass into one shared blob.
+    #[serde(default)]
+    pub team_mode: bool,
+    /// Mass of every team that still has players in the match.
+    #[serde(default)]
+    pub team_sizes: BTreeMap<TeamId, f32>,
+    /// Seats in the room, counting eliminated players who are still watching.
+    #[serde(default = "default_max_players")]
+    pub max_players: usize,
+}
+
+fn first_match_number() -> u64 {
+    1
+}
+
+fn default_max_players() -> usize {
+    DEFAULT_MAX_PLAYERS
 }
 
 impl RoomState {
+    pub fn new(room_code: String, game_key: String, win_condition: WinConditionKind) -> Self {
+        Self {
+            room_code,
+            game_key,
+            players: HashMap::new(),
+            prompt: None,
+            round_id: 0,
+            round_ends_at_ms: None,
+            match_winner: None,
+            next_player_id: 1,
+            win_condition,
+            match_started_at_ms: None,
+            phase: RoomPhase::Lobby,
+            host_id: None,
+            eliminated: HashMap::new(),
+            match_number: 1,
+            match_start_round_id: 0,
+            team_mode: false,
+            team_sizes: BTreeMap::new(),
+            max_players: DEFAULT_MAX_PLAYERS,
+        }
+    }
+
+    /// Rounds dealt so far in the current match.
+    pub fn rounds_in_match(&self) -> u64 {
+        self.round_id - self.match_start_round_id
+    }
+
+    /// Whether `name` is already used by someone in the room, ignoring case.
+    pub fn name_taken(&self, name: &str) -> bool {
+        let name = name.trim().to_lowercase();
+        self.players
+            .values()
+            .chain(
+                self.eliminated
+                    .values()
+                    .map(|eliminated| &eliminated.player),
+            )
+            .any(|player| player.name.trim().to_lowercase() == name)
+    }
+
+    /// Adds a player to the room. A player with a team joins that team's
+    /// blob, which starts at `DEFAULT_START_SIZE` for a new team.
+    pub fn add_player(&mut self, mut player: PlayerState) {
+        if let Some(team) = &player.team {
+            player.size = *self
+                .team_sizes
+                .entry(team.clone())
+                .or_insert(DEFAULT_START_SIZE);
+        }
+        self.players.insert(player.id, player);
+    }
+
+    /// Ids of the players sharing `player_id`'s blob: the player alone, or
+    /// every teammate still in play.
+    pub fn blob_members(&self, player_id: PlayerId) -> Vec<PlayerId> {
+        let Some(player) = self.players.get(&player_id) else {
+            return Vec::new();
+        };
+        let mut members: Vec<PlayerId> = self
+            .players
+            .values()
+            .filter(|other| same_blob(player, other))
+            .map(|other| other.id)
+            .collect();
+        members.sort_unstable();
+        members
+    }
+
+    /// Resizes the blob `player_id` plays for and returns its new size.
+    /// Teammates' sizes are kept in step with their team's mass.
+    pub fn resize_blob(
+        &mut self,
+        player_id: PlayerId,
+        resize: impl FnOnce(f32) -> f32,
+    ) -> Option<f32> {
+        let player = self.players.get_mut(&player_id)?;
+        let size = resize(player.size);
+        player.size = size;
+        if let Some(team) = player.team.clone() {
+            for member in self.players.values_mut() {
+                if member.team.as_ref() == Some(&team) {
+                    member.size = size;
+                }
+            }
+            self.team_sizes.insert(team, size);
+        }
+        Some(size)
+    }
+
+    /// Every blob still competing for the match as `(id, size)`. A team is
+    /// represented by its lowest member id.
+    pub fn contenders(&self) -> Vec<(PlayerId, f32)> {
+        let mut contenders: Vec<(PlayerId, f32)> = Vec::new();
+        let mut team_representatives: BTreeMap<&TeamId, (PlayerId, f32)> = BTreeMap::new();
+        for player in self.players.values() {
+            match &player.team {
+                Some(team) => {
+                    let entry = team_representatives
+                        .entry(team)
+                        .or_insert((player.id, player.size));
+                    entry.0 = entry.0.min(player.id);
+                }
+                None => contenders.push((player.id, player.size)),
+            }
+        }
+        contenders.extend(team_representatives.into_values());
+        contenders.sort_by_key(|(id, _)| *id);
+        contenders
+    }
+
+    /// Removes a player from play for the rest of the match, keeping them in
+    /// `eliminated` so they can keep watching.
+    pub fn eliminate_player(&mut self, player_id: PlayerId, eaten_by: Option<PlayerId>) {
+        if let Some(mut player) = self.players.remove(&player_id) {
+            player.progress.clear();
+            if let Some(team) = &player.team
+                && !self.players.values().any(|p| p.team.as_ref() == Some(team))
+            {
+                self.team_sizes.remove(team);
+            }
+            self.eliminated.insert(
+                player_id,
+                EliminatedPlayer {
+                    player,
+                    eliminated_in_round: self.round_id,
+                    eaten_by,
+                },
+            );
+        }
+    }
+
+    /// Players, active or eliminated, who have been disconnected for at least
+    /// `grace_ms`, in id order.
+    pub fn expired_players(&self, now_ms: u64, grace_ms: u64) -> Vec<PlayerId> {
+        let mut expired: Vec<PlayerId> = self
+            .players
+            .values()
+            .chain(
+                self.eliminated
+                    .values()
+                    .map(|eliminated| &eliminated.player),
+            )
+            .filter(|player| {
+                !player.connected
+                    && player
+                        .disconnected_at_ms
+                        .is_some_and(|since| now_ms.saturating_sub(since) >= grace_ms)
+            })
+            .map(|player| player.id)
+            .collect();
+        expired.sort_unstable();
+        expired
+    }
+
+    /// When the next disconnected player runs out of grace.
+    pub fn next_expiry_ms(&self, grace_ms: u64) -> Option<u64> {
+        self.players
+            .values()
+            .chain(
+                self.eliminated
+                    .values()
+                    .map(|eliminated| &eliminated.player),
+            )
+            .filter(|player| !player.connected)
+            .filter_map(|player| player.disconnected_at_ms)
+            .min()
+            .map(|since| since + grace_ms)
+    }
+
+    /// Removes a player from the room for good, freeing their seat. Works for
+    /// eliminated players too.
+    pub fn remove_player(&mut self, player_id: PlayerId) -> Option<PlayerState> {
+        let player = match self.players.remove(&player_id) {
+            Some(player) => player,
+            None => self.eliminated.remove(&player_id)?.player,
+        };
+        if let Some(team) = &player.team
+            && !self.players.values().any(|p| p.team.as_ref() == Some(team))
+        {
+            self.team_sizes.remove(team);
+        }
+        self.ensure_host();
+        Some(player)
+    }
+
+    /// Resets the room for another match under the same room code. Every
+    /// player starts over at `DEFAULT_START_SIZE`, and eliminated players for
+    /// whom `still_connected` holds are brought back into play. Returns the
+    /// ids of the restored players.
+    pub fn reset_for_rematch(
+        &mut self,
+        still_connected: impl Fn(PlayerId) -> bool,
+    ) -> Vec<PlayerId> {
+        let mut restored = Vec::new();
+        for (player_id, eliminated) in std::mem::take(&mut self.eliminated) {
+            if still_connected(player_id) {
+                restored.push(player_id);
+                self.players.insert(player_id, eliminated.player);
+            }
+        }
+        restored.sort_unstable();
+
+        self.team_sizes.clear();
+        for player in self.players.values_mut() {
+            if let Some(team) = &player.team {
+                self.team_sizes.insert(team.clone(), DEFAULT_START_SIZE);
+            }
+            player.size = DEFAULT_START_SIZE;
+            player.progress.clear();
+            player.partial_credit_claimed = false;
+            player.wrong_attempts = 0;
+            player.locked_until_ms = None;
+        }
+        self.prompt = None;
+        self.round_ends_at_ms = None;
+        self.match_winner = None;
+        self.match_started_at_ms = None;
+        self.match_start_round_id = self.round_id;
+        self.match_number += 1;
+        self.phase = RoomPhase::Playing;
+        self.ensure_host();
+        restored
+    }
+
+    /// Re-evaluates the room's win condition and records the winner, if any.
+    pub fn update_match_winner(&mut self, now_ms: u64) -> Option<PlayerId> {
+        if self.match_winner.is_none() {
+            self.match_winner = self.win_condition.match_winner(self, now_ms);
+        }
+        if self.match_winner.is_some() {
+            self.phase = RoomPhase::Finished;
+        }
+        self.match_winner
+    }
+
+    /// Hands the host role to the longest-standing connected player when the
+    /// current host has left or been removed from the room.
+    pub fn ensure_host(&mut self) -> Option<PlayerId> {
+        let host_present = self
+            .host_id
+            .and_then(|id| self.players.get(&id))
+            .is_some_and(|host| host.connected);
+        if !host_present {
+            let connected = self.players.values().filter(|p| p.connected).map(|p| p.id);
+            self.host_id = connected
+                .min()
+                .or_else(|| self.host_id.filter(|id| self.players.contains_key(id)))
+                .or_else(|| self.players.keys().min().copied());
+        }
+        self.host_id
+    }
+
     pub fn to_snapshot(&self) -> RoomSnapshot {
         let mut players: Vec<PlayerSnapshot> = self
             .players
@@ -70,13 +654,56 @@ impl RoomState {
             .map(PlayerState::to_snapshot)
             .collect();
         players.sort_by_key(|p| p.id);
+        let mut eliminated: Vec<EliminatedSnapshot> = self
+            .eliminated
+            .values()
+            .map(EliminatedPlayer::to_snapshot)
+            .collect();
+        eliminated.sort_by_key(|p| p.id);
+        let teams = self
+            .team_sizes
+            .iter()
+            .map(|(name, size)| TeamSnapshot {
+                name: name.clone(),
+                size: *size,
+                member_ids: players
+                    .iter()
+                    .filter(|p| p.team.as_ref() == Some(name))
+                    .map(|p| p.id)
+                    .collect(),
+            })
+            .collect();
+        let winning_team = self
+            .match_winner
+            .and_then(|id| self.players.get(&id))
+            .and_then(|winner| winner.team.clone());
 
         RoomSnapshot {
             room_code: self.room_code.clone(),
             players,
-            prompt: self.prompt.clone(),
+            eliminated,
+            prompt: self
+                .prompt
+                .as_ref()
+                .map(|prompt| prompt.display.clone())
+                .unwrap_or_default(),
+            prompt_metadata: self
+                .prompt
+                .as_ref()
+                .map(|prompt| prompt.metadata.clone())
+                .unwrap_or_default(),
             round_id: self.round_id,
+            round_ends_at_ms: self.round_ends_at_ms,
             match_winner: self.match_winner,
+            win_condition: self.win_condition,
+            match_ends_at_ms: self.win_condition.deadline_ms(self),
+            phase: self.phase,
+            host_id: self.host_id,
+            match_number: self.match_number,
+            team_mode: self.team_mode,
+            teams,
+            winning_team,
+            max_players: self.max_players,
         }
     }
 }
@@ -94,27 +721,30 @@ pub fn apply_round_win(
     winner_id: PlayerId,
     awarded_growth: f32,
     min_eatable_size: f32,
+    now_ms: u64,
 ) -> Option<RoundResolution> {
+    let winner_size = room.resize_blob(winner_id, |size| size + awarded_growth)?;
     let winner = room.players.get_mut(&winner_id)?;
-    winner.size += awarded_growth;
     winner.progress.clear();
 
-    let winner_size = winner.size;
-    let consumed_player_ids = if winner_size >= min_eatable_size {
+    let winner = &room.players[&winner_id];
+    let mut consumed_player_ids = if winner_size >= min_eatable_size {
         room.players
             .values()
-            .filter(|p| p.id != winner_id && p.size < winner_size)
+            .filter(|p| !same_blob(winner, p) && p.size < winner_size)
             .map(|p| p.id)
             .collect::<Vec<_>>()
     } else {
         Vec::new()
     };
+    consumed_player_ids.sort_unstable();
 
     for player_id in &consumed_player_ids {
-        room.players.remove(player_id);
+        room.eliminate_player(*player_id, Some(winner_id));
     }
+    room.ensure_host();
 
-    room.match_winner = evaluate_match_winner(&room.players);
+    room.update_match_winner(now_ms);
     Some(RoundResolution {
         round_winner: winner_id,
         consumed_player_ids,
@@ -122,26 +752,190 @@ pub fn apply_round_win(
     })
 }
 
-pub fn evaluate_match_winner(players: &HashMap<PlayerId, PlayerState>) -> Option<PlayerId> {
-    if players.len() < 2 {
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PartialCreditResolution {
+    pub player_id: PlayerId,
+    pub growth_awarded: f32,
+    pub match_winner: Option<PlayerId>,
+}
+
+/// Grows a player for a partially correct attempt without ending the round.
+/// Each blob can claim partial credit at most once per round, so teammates
+/// share one claim, and partial credit never consumes other players.
+pub fn apply_partial_credit(
+    room: &mut RoomState,
+    player_id: PlayerId,
+    awarded_growth: f32,
+    now_ms: u64,
+) -> Option<PartialCreditResolution> {
+    let members = room.blob_members(player_id);
+    if members.is_empty()
+        || members
+            .iter()
+            .any(|id| room.players[id].partial_credit_claimed)
+    {
+        return None;
+    }
+    for id in &members {
+        if let Some(member) = room.players.get_mut(id) {
+            member.partial_credit_claimed = true;
+        }
+    }
+    room.resize_blob(player_id, |size| size + awarded_growth);
+
+    room.update_match_winner(now_ms);
+    Some(PartialCreditResolution {
+        player_id,
+        growth_awarded: awarded_growth,
+        match_winner: room.match_winner,
+    })
+}
+
+/// How a wrong answer is punished. Configured server-wide through
+/// `ServerConfig::wrong_answer_penalty`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WrongAnswerPenalty {
+    #[default]
+    None,
+    /// Shrink by a fixed amount of size per wrong answer.
+    FixedShrink(f32),
+    /// Shrink by a percentage (0-100) of the current size per wrong answer.
+    PercentShrink(f32),
+    /// Block submissions for `cooldown_ms` once a player has answered wrong
+    /// `after_attempts` times in the current round.
+    Lockout {
+        after_attempts: u32,
+        cooldown_ms: u64,
+    },
+}
+
+impl FromStr for WrongAnswerPenalty {
+    type Err = String;
+
+    /// Parses `none`, `fixed:<size>`, `percent:<pct>` or
+    /// `lockout:<attempts>:<cooldown_ms>`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = value.trim().split(':').collect();
+        let invalid = || format!("invalid wrong answer penalty: {value}");
+        match parts.as_slice() {
+            ["none"] => Ok(Self::None),
+            ["fixed", amount] => amount
+                .parse::<f32>()
+                .map(Self::FixedShrink)
+                .map_err(|_| invalid()),
+            ["percent", pct] => pct
+                .parse::<f32>()
+                .ok()
+                .filter(|pct| (0.0..=100.0).contains(pct))
+                .map(Self::PercentShrink)
+                .ok_or_else(invalid),
+            ["lockout", attempts, cooldown_ms] => {
+                let after_attempts = attempts.parse::<u32>().map_err(|_| invalid())?;
+                let cooldown_ms = cooldown_ms.parse::<u64>().map_err(|_| invalid())?;
+                Ok(Self::Lockout {
+                    after_attempts: after_attempts.max(1),
+                    cooldown_ms,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PenaltyResolution {
+    pub player_id: PlayerId,
+    pub size_lost: f32,
+    pub locked_until_ms: Option<u64>,
+    pub eliminated: bool,
+    /// Everyone removed along with the player: their whole team in team rooms.
+    pub eliminated_player_ids: Vec<PlayerId>,
+    pub match_winner: Option<PlayerId>,
+}
+
+/// Applies the configured penalty for a wrong answer. Players that shrink
+/// below `elimination_size` are removed from the room, just like players
+/// consumed by a round winner. In team rooms the penalty shrinks the shared
+/// team blob, so the whole team is removed together.
+pub fn apply_wrong_answer(
+    room: &mut RoomState,
+    player_id: PlayerId,
+    penalty: WrongAnswerPenalty,
+    elimination_size: f32,
+    now_ms: u64,
+) -> Option<PenaltyResolution> {
+    let player = room.players.get_mut(&player_id)?;
+    player.wrong_attempts += 1;
+
+    if let WrongAnswerPenalty::Lockout {
+        after_attempts,
+        cooldown_ms,
+    } = penalty
+        && player.wrong_attempts >= after_attempts
+    {
+        player.wrong_attempts = 0;
+        player.locked_until_ms = Some(now_ms.saturating_add(cooldown_ms));
+    }
+    let locked_until_ms = player.locked_until_ms.filter(|until| now_ms < *until);
+
+    let size_before = player.size;
+    let size_after = room.resize_blob(player_id, |size| match penalty {
+        WrongAnswerPenalty::FixedShrink(amount) => (size - amount.max(0.0)).max(0.0),
+        WrongAnswerPenalty::PercentShrink(pct) => size - size * pct.clamp(0.0, 100.0) / 100.0,
+        WrongAnswerPenalty::None | WrongAnswerPenalty::Lockout { .. } => size,
+    })?;
+    let size_lost = size_before - size_after;
+    let eliminated = size_lost > 0.0 && size_after < elimination_size;
+
+    let mut eliminated_player_ids = Vec::new();
+    if eliminated {
+        eliminated_player_ids = room.blob_members(player_id);
+        for id in &eliminated_player_ids {
+            room.eliminate_player(*id, None);
+        }
+        room.ensure_host();
+        room.update_match_winner(now_ms);
+    }
+    Some(PenaltyResolution {
+        player_id,
+        size_lost,
+        locked_until_ms,
+        eliminated,
+        eliminated_player_ids,
+        match_winner: room.match_winner,
+    })
+}
+
+/// Whether two players play for the same blob: they are the same player,
+/// or teammates.
+fn same_blob(a: &PlayerState, b: &PlayerState) -> bool {
+    a.id == b.id || (a.team.is_some() && a.team == b.team)
+}
+
+/// The default `Dominance` rule over `RoomState::contenders`, see
+/// `win_condition::Dominance`.
+pub fn evaluate_match_winner(contenders: &[(PlayerId, f32)]) -> Option<PlayerId> {
+    if contenders.len() < 2 {
         return None;
     }
 
-    let mut ranked: Vec<&PlayerState> = players.values().collect();
-    ranked.sort_by(|a, b| b.size.total_cmp(&a.size));
-    let largest = ranked[0];
+    let mut ranked: Vec<(PlayerId, f32)> = contenders.to_vec();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let (largest_id, largest_size) = ranked[0];
 
     if ranked.len() == 2 {
-        let other = ranked[1];
-        if largest.size > other.size * 2.0 {
-            return Some(largest.id);
+        let (_, other_size) = ranked[1];
+        if largest_size > other_size * 2.0 {
+            return Some(largest_id);
         }
         return None;
     }
 
-    let sum_others: f32 = ranked.iter().skip(1).map(|p| p.size).sum();
-    if largest.size > sum_others {
-        return Some(largest.id);
+    let sum_others: f32 = ranked.iter().skip(1).map(|(_, size)| size).sum();
+    if largest_size > sum_others {
+        return Some(largest_id);
     }
 
     None
@@ -153,53 +947,358 @@ mod tests {
 
     fn player(id: PlayerId, size: f32) -> PlayerState {
         PlayerState {
-            id,
-            name: format!("p{id}"),
             size,
-            color: "#ffffff".to_string(),
-            connected: true,
-            progress: String::new(),
-            rejoin_token: String::new(),
+            ..PlayerState::new(id, format!("p{id}"), "#ffffff".to_string(), String::new())
         }
     }
 
+    fn room_with(players: Vec<PlayerState>) -> RoomState {
+        let mut room = RoomState::new(
+            "ABCD".to_string(),
+            "keyboarding".to_string(),
+            WinConditionKind::Dominance,
+        );
+        room.next_player_id = players.iter().map(|p| p.id).max().unwrap_or(0) + 1;
+        room.players = players.into_iter().map(|p| (p.id, p)).collect();
+        room.prompt = Some(Prompt::new("abc", "abc"));
+        room.round_id = 1;
+        room
+    }
+
     #[test]
     fn two_player_win_requires_double_size() {
-        let mut players = HashMap::new();
-        players.insert(1, player(1, 30.0));
-        players.insert(2, player(2, 14.9));
-        assert_eq!(evaluate_match_winner(&players), Some(1));
-
-        players.insert(2, player(2, 15.1));
-        assert_eq!(evaluate_match_winner(&players), None);
+        assert_eq!(evaluate_match_winner(&[(1, 30.0), (2, 14.9)]), Some(1));
+        assert_eq!(evaluate_match_winner(&[(1, 30.0), (2, 15.1)]), None);
     }
 
     #[test]
     fn multi_player_win_requires_largest_gt_sum_of_others() {
-        let mut players = HashMap::new();
-        players.insert(1, player(1, 35.0));
-        players.insert(2, player(2, 18.0));
-        players.insert(3, player(3, 16.0));
-        assert_eq!(evaluate_match_winner(&players), Some(1));
-
-        players.insert(3, player(3, 18.0));
-        assert_eq!(evaluate_match_winner(&players), None);
+        assert_eq!(
+            evaluate_match_winner(&[(1, 35.0), (2, 18.0), (3, 16.0)]),
+            Some(1)
+        );
+        assert_eq!(
+            evaluate_match_winner(&[(1, 35.0), (2, 18.0), (3, 18.0)]),
+            None
+        );
     }
 
     #[test]
     fn minimum_size_gate_blocks_consumption() {
-        let mut room = RoomState {
-            room_code: "ABCD".to_string(),
-            game_key: "keyboarding".to_string(),
-            players: HashMap::from([(1, player(1, 10.0)), (2, player(2, 9.0))]),
-            prompt: "abc".to_string(),
-            round_id: 1,
-            match_winner: None,
-            next_player_id: 3,
-        };
+        let mut room = room_with(vec![player(1, 10.0), player(2, 9.0)]);
 
-        let resolution = apply_round_win(&mut room, 1, 1.0, MIN_EATABLE_SIZE).expect("resolution");
+        let resolution =
+            apply_round_win(&mut room, 1, 1.0, MIN_EATABLE_SIZE, 0).expect("resolution");
         assert!(resolution.consumed_player_ids.is_empty());
         assert!(room.players.contains_key(&2));
     }
+
+    #[test]
+    fn snapshot_exposes_prompt_display_without_answer() {
+        let mut room = room_with(vec![player(1, 10.0)]);
+        room.prompt = Some(Prompt::new("2 + 9", 11).with_metadata("operation", "addition"));
+
+        let encoded = serde_json::to_value(room.to_snapshot()).expect("encode snapshot");
+        assert_eq!(encoded["prompt"], "2 + 9");
+        assert_eq!(encoded["promptMetadata"]["operation"], "addition");
+        assert!(!encoded.to_string().contains("11"));
+    }
+
+    #[test]
+    fn partial_credit_is_awarded_once_per_round() {
+        let mut room = room_with(vec![player(1, 10.0), player(2, 10.0)]);
+
+        let resolution = apply_partial_credit(&mut room, 1, 2.0, 0).expect("resolution");
+        assert_eq!(resolution.growth_awarded, 2.0);
+        assert_eq!(room.players[&1].size, 12.0);
+        assert!(apply_partial_credit(&mut room, 1, 2.0, 0).is_none());
+        assert_eq!(room.players[&1].size, 12.0);
+        assert_eq!(room.players[&2].size, 10.0);
+    }
+
+    #[test]
+    fn parses_wrong_answer_penalties() {
+        assert_eq!("none".parse(), Ok(WrongAnswerPenalty::None));
+        assert_eq!("fixed:2".parse(), Ok(WrongAnswerPenalty::FixedShrink(2.0)));
+        assert_eq!(
+            "percent:10".parse(),
+            Ok(WrongAnswerPenalty::PercentShrink(10.0))
+        );
+        assert_eq!(
+            "lockout:3:5000".parse(),
+            Ok(WrongAnswerPenalty::Lockout {
+                after_attempts: 3,
+                cooldown_ms: 5000
+            })
+        );
+        assert!("percent:150".parse::<WrongAnswerPenalty>().is_err());
+        assert!("shrink".parse::<WrongAnswerPenalty>().is_err());
+    }
+
+    #[test]
+    fn percent_shrink_eliminates_below_floor() {
+        let mut room = room_with(vec![player(1, 10.0), player(2, 10.0)]);
+        let penalty = WrongAnswerPenalty::PercentShrink(40.0);
+
+        let first = apply_wrong_answer(&mut room, 1, penalty, MIN_PLAYER_SIZE, 0).expect("first");
+        assert_eq!(first.size_lost, 4.0);
+        assert!(!first.eliminated);
+        assert_eq!(room.players[&1].size, 6.0);
+
+        let second = apply_wrong_answer(&mut room, 1, penalty, MIN_PLAYER_SIZE, 0).expect("second");
+        assert!(second.eliminated);
+        assert!(!room.players.contains_key(&1));
+        assert_eq!(room.eliminated[&1].eaten_by, None);
+    }
+
+    #[test]
+    fn lockout_triggers_after_configured_attempts() {
+        let mut room = room_with(vec![player(1, 10.0)]);
+        let penalty = WrongAnswerPenalty::Lockout {
+            after_attempts: 2,
+            cooldown_ms: 3_000,
+        };
+
+        let first =
+            apply_wrong_answer(&mut room, 1, penalty, MIN_PLAYER_SIZE, 1_000).expect("first");
+        assert_eq!(first.locked_until_ms, None);
+
+        let second =
+            apply_wrong_answer(&mut room, 1, penalty, MIN_PLAYER_SIZE, 1_000).expect("second");
+        assert_eq!(second.locked_until_ms, Some(4_000));
+        assert_eq!(second.size_lost, 0.0);
+        assert!(room.players[&1].is_locked_out(3_999));
+        assert!(!room.players[&1].is_locked_out(4_000));
+    }
+
+    #[test]
+    fn host_passes_to_longest_standing_connected_player() {
+        let mut room = room_with(vec![player(1, 10.0), player(2, 10.0), player(3, 10.0)]);
+        assert_eq!(room.ensure_host(), Some(1));
+
+        room.players.get_mut(&1).expect("player").connected = false;
+        assert_eq!(room.ensure_host(), Some(2));
+
+        room.players.get_mut(&1).expect("player").connected = true;
+        assert_eq!(room.ensure_host(), Some(2));
+
+        room.players.remove(&2);
+        assert_eq!(room.ensure_host(), Some(1));
+    }
+
+    #[test]
+    fn declaring_a_match_winner_finishes_the_room() {
+        let mut room = room_with(vec![player(1, 30.0), player(2, 10.0)]);
+        room.phase = RoomPhase::Playing;
+        assert_eq!(room.update_match_winner(0), Some(1));
+        assert_eq!(room.phase, RoomPhase::Finished);
+    }
+
+    #[test]
+    fn consumed_players_are_recorded_as_eliminated() {
+        let mut room = room_with(vec![player(1, 20.0), player(2, 10.0)]);
+        room.round_id = 3;
+        apply_round_win(&mut room, 1, 4.0, MIN_EATABLE_SIZE, 0).expect("resolution");
+
+        let snapshot = room.to_snapshot();
+        assert_eq!(snapshot.players.len(), 1);
+        assert_eq!(snapshot.eliminated.len(), 1);
+        assert_eq!(snapshot.eliminated[0].id, 2);
+        assert_eq!(snapshot.eliminated[0].eaten_by, Some(1));
+        assert_eq!(snapshot.eliminated[0].eliminated_in_round, 3);
+    }
+
+    #[test]
+    fn rematch_restores_connected_consumed_players() {
+        let mut room = room_with(vec![player(1, 30.0), player(2, 10.0), player(3, 10.0)]);
+        room.phase = RoomPhase::Playing;
+        room.round_id = 4;
+        let resolution =
+            apply_round_win(&mut room, 1, 10.0, MIN_EATABLE_SIZE, 0).expect("resolution");
+        assert_eq!(resolution.consumed_player_ids.len(), 2);
+        assert_eq!(room.players.len(), 1);
+        assert_eq!(room.eliminated.len(), 2);
+        room.match_winner = Some(1);
+        room.phase = RoomPhase::Finished;
+
+        let restored = room.reset_for_rematch(|id| id == 2);
+        assert_eq!(restored, vec![2]);
+        assert!(room.eliminated.is_empty());
+        assert_eq!(room.players.len(), 2);
+        assert!(room.players.values().all(|p| p.size == DEFAULT_START_SIZE));
+        assert_eq!(room.match_winner, None);
+        assert_eq!(room.phase, RoomPhase::Playing);
+        assert_eq!(room.match_number, 2);
+        assert_eq!(room.rounds_in_match(), 0);
+        assert!(room.prompt.is_none());
+    }
+
+    fn team_room(members: &[(PlayerId, &str)]) -> RoomState {
+        let mut room = room_with(Vec::new());
+        room.team_mode = true;
+        for (id, team) in members {
+            room.add_player(PlayerState {
+                team: Some(team.to_string()),
+                ..player(*id, DEFAULT_START_SIZE)
+            });
+        }
+        room.next_player_id = members.iter().map(|(id, _)| id + 1).max().unwrap_or(1);
+        room
+    }
+
+    #[test]
+    fn team_round_win_grows_shared_mass_and_eats_whole_teams() {
+        let mut room = team_room(&[(1, "red"), (2, "red"), (3, "blue"), (4, "blue")]);
+        room.resize_blob(1, |_| 16.0);
+        assert_eq!(room.players[&2].size, 16.0);
+
+        let resolution =
+            apply_round_win(&mut room, 2, 4.0, MIN_EATABLE_SIZE, 0).expect("resolution");
+        assert_eq!(resolution.consumed_player_ids, vec![3, 4]);
+        assert_eq!(room.team_sizes.get("red"), Some(&20.0));
+        assert!(!room.team_sizes.contains_key("blue"));
+        assert_eq!(room.players[&1].size, 20.0);
+        assert_eq!(room.eliminated[&3].eaten_by, Some(2));
+    }
+
+    #[test]
+    fn teammates_share_one_partial_credit_claim_per_round() {
+        let mut room = team_room(&[(1, "red"), (2, "red"), (3, "blue")]);
+
+        let resolution = apply_partial_credit(&mut room, 1, 2.0, 0).expect("resolution");
+        assert_eq!(resolution.growth_awarded, 2.0);
+        assert!(apply_partial_credit(&mut room, 2, 2.0, 0).is_none());
+        assert_eq!(
+            room.team_sizes.get("red"),
+            Some(&(DEFAULT_START_SIZE + 2.0))
+        );
+        assert!(room.players[&2].partial_credit_claimed);
+
+        assert!(apply_partial_credit(&mut room, 3, 2.0, 0).is_some());
+        assert_eq!(
+            room.team_sizes.get("blue"),
+            Some(&(DEFAULT_START_SIZE + 2.0))
+        );
+    }
+
+    #[test]
+    fn team_win_conditions_compare_teams_not_players() {
+        let mut room = team_room(&[(1, "red"), (2, "blue"), (3, "blue"), (4, "green")]);
+        room.resize_blob(1, |_| 25.0);
+        room.resize_blob(2, |_| 12.0);
+        assert_eq!(room.contenders(), vec![(1, 25.0), (2, 12.0), (4, 10.0)]);
+        assert_eq!(room.update_match_winner(0), Some(1));
+
+        let snapshot = room.to_snapshot();
+        assert_eq!(snapshot.winning_team.as_deref(), Some("red"));
+        let blue = snapshot
+            .teams
+            .iter()
+            .find(|team| team.name == "blue")
+            .expect("blue team");
+        assert_eq!(blue.size, 12.0);
+        assert_eq!(blue.member_ids, vec![2, 3]);
+    }
+
+    #[test]
+    fn team_penalty_shrinks_and_eliminates_the_whole_team() {
+        let mut room = team_room(&[(1, "red"), (2, "red"), (3, "blue")]);
+        let resolution = apply_wrong_answer(
+            &mut room,
+            1,
+            WrongAnswerPenalty::FixedShrink(6.0),
+            MIN_PLAYER_SIZE,
+            0,
+        )
+        .expect("resolution");
+        assert!(resolution.eliminated);
+        assert_eq!(resolution.eliminated_player_ids, vec![1, 2]);
+        assert_eq!(room.players.keys().copied().collect::<Vec<_>>(), vec![3]);
+        assert_eq!(room.eliminated[&2].eaten_by, None);
+    }
+
+    #[test]
+    fn rematch_resets_team_mass() {
+        let mut room = team_room(&[(1, "red"), (2, "blue")]);
+        room.resize_blob(1, |_| 40.0);
+        room.eliminate_player(2, Some(1));
+        room.match_winner = Some(1);
+        room.phase = RoomPhase::Finished;
+
+        room.reset_for_rematch(|_| true);
+        assert_eq!(room.team_sizes.get("red"), Some(&DEFAULT_START_SIZE));
+        assert_eq!(room.team_sizes.get("blue"), Some(&DEFAULT_START_SIZE));
+        assert_eq!(room.players[&1].size, DEFAULT_START_SIZE);
+    }
+
+    #[test]
+    fn player_changes_patch_only_changed_fields() {
+        let mut room = room_with(vec![player(1, 10.0), player(2, 10.0)]);
+        let before = room.to_snapshot();
+        room.players.get_mut(&1).expect("player").size = 14.0;
+        room.players.remove(&2);
+        room.players.insert(3, player(3, 10.0));
+        let after = room.to_snapshot();
+
+        let (patches, removed) = before.player_changes(&after).expect("player-only change");
+        assert_eq!(removed, vec![2]);
+        assert_eq!(
+            patches[0],
+            PlayerPatch {
+                id: 1,
+                size: Some(14.0),
+                ..PlayerPatch::default()
+            }
+        );
+        assert_eq!(patches[1].name.as_deref(), Some("p3"));
+
+        let rebuilt: Vec<PlayerSnapshot> = after
+            .players
+            .iter()
+            .map(|player| {
+                let old = before.players.iter().find(|old| old.id == player.id);
+                match patches.iter().find(|patch| patch.id == player.id) {
+                    Some(patch) => patch.apply(old).expect("patch applies"),
+                    None => old.expect("unchanged player").clone(),
+                }
+            })
+            .collect();
+        assert_eq!(rebuilt, after.players);
+
+        room.round_id += 1;
+        assert!(after.player_changes(&room.to_snapshot()).is_none());
+    }
+
+    #[test]
+    fn disconnected_players_expire_after_the_grace_window() {
+        let mut room = room_with(vec![player(1, 10.0), player(2, 10.0), player(3, 10.0)]);
+        room.host_id = Some(1);
+        for id in [1, 2] {
+            let player = room.players.get_mut(&id).unwrap();
+            player.connected = false;
+            player.disconnected_at_ms = Some(1_000 * id);
+        }
+        room.eliminate_player(2, Some(3));
+
+        assert_eq!(room.next_expiry_ms(5_000), Some(6_000));
+        assert!(room.expired_players(5_999, 5_000).is_empty());
+        assert_eq!(room.expired_players(6_000, 5_000), vec![1]);
+        assert_eq!(room.expired_players(7_000, 5_000), vec![1, 2]);
+
+        assert!(room.remove_player(1).is_some());
+        assert!(room.remove_player(2).is_some());
+        assert!(room.remove_player(2).is_none());
+        assert!(room.eliminated.is_empty());
+        assert_eq!(room.host_id, Some(3));
+        assert_eq!(room.next_expiry_ms(5_000), None);
+    }
+
+    #[test]
+    fn removing_a_teams_last_player_drops_its_mass() {
+        let mut room = team_room(&[(1, "red"), (2, "red"), (3, "blue")]);
+        room.remove_player(3);
+        assert!(!room.team_sizes.contains_key("blue"));
+        room.remove_player(1);
+        assert!(room.team_sizes.contains_key("red"));
+    }
 }
@@ -1,7 +1,12 @@
 pub mod adapter;
+pub mod events;
 pub mod game;
+pub mod limits;
 pub mod protocol;
 pub mod server;
+pub mod store;
+pub mod win_condition;
 
-pub use adapter::{AdapterHandle, GameAdapter};
-pub use server::{ServerConfig, run_server};
+pub use adapter::{AdapterHandle, GameAdapter, Grade, Prompt};
+pub use server::{ServerConfig, run_server, run_server_with_store};
+pub use store::{FileRoomStore, InMemoryRoomStore, RoomStore};
@@ -0,0 +1,247 @@
+use crate::protocol::ClientMessage;
+
+/// A token bucket allowance: bursts of up to `burst` messages, refilled at
+/// `per_second`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f32,
+    last_refill_ms: u64,
+}
+
+impl TokenBucket {
+    /// A bucket that starts full.
+    pub fn new(limit: RateLimit, now_ms: u64) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f32,
+            last_refill_ms: now_ms,
+        }
+    }
+
+    /// Takes one token if available.
+    pub fn try_take(&mut self, now_ms: u64) -> bool {
+        let elapsed_secs = now_ms.saturating_sub(self.last_refill_ms) as f32 / 1000.0;
+        self.tokens =
+            (self.tokens + elapsed_secs * self.limit.per_second).min(self.limit.burst as f32);
+        self.last_refill_ms = now_ms;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether the last take used up every token.
+    pub fn is_empty(&self) -> bool {
+        self.tokens < 1.0
+    }
+}
+
+/// Limits applied to every client connection. Configured through
+/// `ServerConfig::limits`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionLimits {
+    pub input_updates: RateLimit,
+    pub submissions: RateLimit,
+    /// Every other client message: handshake, joins, match control.
+    pub other_messages: RateLimit,
+    /// Longest `text` accepted in `InputUpdate` and `SubmitAttempt`.
+    pub max_text_chars: usize,
+    /// Largest frame handled. Frames up to twice this size are refused with
+    /// an error; anything bigger is dropped by the socket itself.
+    pub max_frame_bytes: usize,
+    /// Violations tolerated before the connection is closed. The allowance
+    /// refills over time, so only a client that keeps misbehaving is cut off.
+    pub violations: RateLimit,
+    /// Outbound messages a connection may have waiting to be written. Stale
+    /// progress is dropped once the queue is three quarters full, and a
+    /// client whose queue fills up is disconnected.
+    pub max_queued_messages: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            input_updates: RateLimit {
+                burst: 40,
+                per_second: 25.0,
+            },
+            submissions: RateLimit {
+                burst: 5,
+                per_second: 2.0,
+            },
+            other_messages: RateLimit {
+                burst: 10,
+                per_second: 2.0,
+            },
+            max_text_chars: 200,
+            max_frame_bytes: 16 * 1024,
+            violations: RateLimit {
+                burst: 50,
+                per_second: 0.2,
+            },
+            max_queued_messages: 256,
+        }
+    }
+}
+
+/// Why a client message was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    RateLimited,
+    TextTooLong,
+    FrameTooLarge,
+}
+
+/// One connection's buckets and violation allowance.
+#[derive(Debug, Clone)]
+pub struct ConnectionGuard {
+    limits: ConnectionLimits,
+    input_updates: TokenBucket,
+    submissions: TokenBucket,
+    other_messages: TokenBucket,
+    violations: TokenBucket,
+}
+
+impl ConnectionGuard {
+    pub fn new(limits: &ConnectionLimits, now_ms: u64) -> Self {
+        Self {
+            limits: limits.clone(),
+            input_updates: TokenBucket::new(limits.input_updates, now_ms),
+            submissions: TokenBucket::new(limits.submissions, now_ms),
+            other_messages: TokenBucket::new(limits.other_messages, now_ms),
+            violations: TokenBucket::new(limits.violations, now_ms),
+        }
+    }
+
+    /// Checks the size of a raw frame before it is decoded.
+    pub fn check_frame(&mut self, frame_bytes: usize, now_ms: u64) -> Result<(), Violation> {
+        if frame_bytes > self.limits.max_frame_bytes {
+            return Err(self.record(Violation::FrameTooLarge, now_ms));
+        }
+        Ok(())
+    }
+
+    /// Checks a decoded message against its rate limit and the text length
+    /// limit.
+    pub fn check_message(&mut self, message: &ClientMessage, now_ms: u64) -> Result<(), Violation> {
+        let (bucket, text) = match message {
+            ClientMessage::InputUpdate { text } => (&mut self.input_updates, Some(text)),
+            ClientMessage::SubmitAttempt { text } => (&mut self.submissions, Some(text)),
+            _ => (&mut self.other_messages, None),
+        };
+        if !bucket.try_take(now_ms) {
+            return Err(self.record(Violation::RateLimited, now_ms));
+        }
+        if text.is_some_and(|text| text.chars().count() > self.limits.max_text_chars) {
+            return Err(self.record(Violation::TextTooLong, now_ms));
+        }
+        Ok(())
+    }
+
+    /// Whether the connection has misbehaved often enough to be closed.
+    pub fn exhausted(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    fn record(&mut self, violation: Violation, now_ms: u64) -> Violation {
+        self.violations.try_take(now_ms);
+        violation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(text: &str) -> ClientMessage {
+        ClientMessage::InputUpdate {
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn bucket_allows_bursts_then_refills_over_time() {
+        let mut bucket = TokenBucket::new(
+            RateLimit {
+                burst: 2,
+                per_second: 4.0,
+            },
+            0,
+        );
+        assert!(bucket.try_take(0));
+        assert!(bucket.try_take(0));
+        assert!(!bucket.try_take(100));
+        assert!(bucket.try_take(250));
+        assert!(!bucket.try_take(250));
+    }
+
+    #[test]
+    fn message_types_have_separate_buckets() {
+        let limits = ConnectionLimits {
+            submissions: RateLimit {
+                burst: 1,
+                per_second: 1.0,
+            },
+            ..ConnectionLimits::default()
+        };
+        let mut guard = ConnectionGuard::new(&limits, 0);
+        let submit = ClientMessage::SubmitAttempt {
+            text: "abc".to_string(),
+        };
+        assert_eq!(guard.check_message(&submit, 0), Ok(()));
+        assert_eq!(guard.check_message(&submit, 0), Err(Violation::RateLimited));
+        assert_eq!(guard.check_message(&update("ab"), 0), Ok(()));
+    }
+
+    #[test]
+    fn oversized_text_and_frames_count_towards_disconnect() {
+        let limits = ConnectionLimits {
+            max_text_chars: 3,
+            max_frame_bytes: 64,
+            violations: RateLimit {
+                burst: 2,
+                per_second: 0.0,
+            },
+            ..ConnectionLimits::default()
+        };
+        let mut guard = ConnectionGuard::new(&limits, 0);
+        assert_eq!(guard.check_message(&update("abc"), 0), Ok(()));
+        assert_eq!(
+            guard.check_message(&update("abcd"), 0),
+            Err(Violation::TextTooLong)
+        );
+        assert!(!guard.exhausted());
+        assert_eq!(guard.check_frame(65, 0), Err(Violation::FrameTooLarge));
+        assert!(guard.exhausted());
+    }
+
+    #[test]
+    fn scattered_violations_are_forgiven_over_time() {
+        let limits = ConnectionLimits {
+            max_frame_bytes: 64,
+            violations: RateLimit {
+                burst: 2,
+                per_second: 1.0,
+            },
+            ..ConnectionLimits::default()
+        };
+        let mut guard = ConnectionGuard::new(&limits, 0);
+        for second in 0..100 {
+            assert!(guard.check_frame(65, second * 1_000).is_err());
+            assert!(!guard.exhausted());
+        }
+
+        assert!(guard.check_frame(65, 100_000).is_err());
+        assert!(guard.check_frame(65, 100_000).is_err());
+        assert!(guard.exhausted());
+    }
+}
@@ -1,9 +1,101 @@
-use crate::game::{PlayerId, RoomSnapshot};
+//! Wire protocol between the browser client and the server.
+//!
+//! # Compatibility policy
+//!
+//! Every connection opens with `ClientMessage::Hello`, naming the client's
+//! `protocolVersion`. The server accepts any version from
+//! `MIN_PROTOCOL_VERSION` through `PROTOCOL_VERSION` and answers with its own
+//! `ServerMessage::Hello`; anything else is rejected with
+//! `ErrorCode::UnsupportedProtocolVersion` and the connection is closed.
+//!
+//! - Additive changes keep the version: new optional fields, new fields in
+//!   server messages, and new error codes. Clients must ignore fields they do
+//!   not know.
+//! - New message types a peer may not understand are gated behind a
+//!   capability. The server only uses a capability that the client listed in
+//!   its `Hello`.
+//! - Removing or renaming a message or field, or changing what one means,
+//!   bumps `PROTOCOL_VERSION`. `MIN_PROTOCOL_VERSION` is raised only once no
+//!   deployed client still speaks the older version.
+
+use crate::game::{PlayerId, PlayerPatch, RoomSnapshot};
+use crate::win_condition::WinConditionKind;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Version spoken by this build. See the compatibility policy above.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest client version this build still accepts.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Capability for MessagePack binary frames, see `Encoding`.
+pub const MSGPACK_CAPABILITY: &str = "msgpack";
+/// Capability for `RoomStatePatch` updates in place of full snapshots.
+pub const STATE_PATCHES_CAPABILITY: &str = "statePatches";
+/// Capability for `RaceProgressBatch` in place of one `RaceProgress` per
+/// player.
+pub const PROGRESS_BATCH_CAPABILITY: &str = "progressBatch";
+/// Optional features this server can enable for a connection.
+pub const SERVER_CAPABILITIES: &[&str] = &[
+    MSGPACK_CAPABILITY,
+    STATE_PATCHES_CAPABILITY,
+    PROGRESS_BATCH_CAPABILITY,
+];
+
+/// Checks a client's `Hello` and returns the capabilities enabled for the
+/// connection: those both sides support.
+pub fn negotiate(protocol_version: u32, capabilities: &[String]) -> Result<Vec<String>, ErrorCode> {
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version) {
+        return Err(ErrorCode::UnsupportedProtocolVersion);
+    }
+    Ok(capabilities
+        .iter()
+        .filter(|capability| SERVER_CAPABILITIES.contains(&capability.as_str()))
+        .cloned()
+        .collect())
+}
+
+/// How messages are framed on a connection. JSON text frames are the
+/// default; once `msgpack` is negotiated the server sends MessagePack binary
+/// frames. Clients may send either kind of frame at any time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+impl Encoding {
+    /// The encoding to use after a handshake enabled `capabilities`.
+    pub fn negotiated(capabilities: &[String]) -> Self {
+        if capabilities.iter().any(|c| c == MSGPACK_CAPABILITY) {
+            Self::MessagePack
+        } else {
+            Self::Json
+        }
+    }
+}
+
+/// Encodes a message as MessagePack, keeping field names so the layout
+/// matches the JSON form.
+pub fn encode_msgpack<T: Serialize>(message: &T) -> Result<Vec<u8>, String> {
+    rmp_serde::to_vec_named(message).map_err(|e| format!("encode error: {e}"))
+}
+
+pub fn decode_msgpack<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+    rmp_serde::from_slice(bytes).map_err(|e| format!("decode error: {e}"))
+}
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum ClientMessage {
+    /// Must be the first message on every connection.
+    Hello {
+        #[serde(rename = "protocolVersion")]
+        protocol_version: u32,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
     JoinOrCreateRoom {
         #[serde(rename = "playerName")]
         player_name: Option<String>,
@@ -11,11 +103,27 @@ pub enum ClientMessage {
         room_code: Option<String>,
         #[serde(rename = "gameMode")]
         game_mode: Option<String>,
+        #[serde(rename = "winCondition")]
+        win_condition: Option<WinConditionKind>,
+        /// Team to join. Creating a room with a team makes it a team room,
+        /// and everyone joining it must then pick a team.
+        team: Option<String>,
+        /// Seats in a newly created room, capped by the server's limit.
+        #[serde(rename = "maxPlayers")]
+        max_players: Option<usize>,
     },
     RejoinRoom {
         #[serde(rename = "rejoinToken")]
         rejoin_token: String,
     },
+    SpectateRoom {
+        #[serde(rename = "roomCode")]
+        room_code: String,
+    },
+    StartMatch,
+    /// Asks for a full `RoomState`, e.g. after missing a patch.
+    RequestRoomState,
+    RequestRematch,
     InputUpdate {
         text: String,
     },
@@ -24,9 +132,16 @@ pub enum ClientMessage {
     },
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum ServerMessage {
+    /// Reply to `ClientMessage::Hello` with the capabilities enabled for the
+    /// connection.
+    Hello {
+        #[serde(rename = "protocolVersion")]
+        protocol_version: u32,
+        capabilities: Vec<String>,
+    },
     Welcome {
         #[serde(rename = "playerId")]
         player_id: PlayerId,
@@ -39,8 +154,29 @@ pub enum ServerMessage {
         #[serde(rename = "rejoinToken")]
         rejoin_token: String,
     },
-    RoomState {
-        room: RoomSnapshot,
+    SpectatorWelcome {
+        #[serde(rename = "spectatorId")]
+        spectator_id: PlayerId,
+        #[serde(rename = "roomCode")]
+        room_code: String,
+        #[serde(rename = "gameKey")]
+        game_key: String,
+        #[serde(rename = "minEatableSize")]
+        min_eatable_size: f32,
+    },
+    /// Full room state. `seq` numbers every room state update, full or
+    /// patch, so clients can spot a missed one.
+    RoomState { room: RoomSnapshot, seq: u64 },
+    /// Player changes since the update numbered `seq - 1`, sent instead of
+    /// `RoomState` to clients with the `statePatches` capability. A client
+    /// that sees a gap in `seq` should send `RequestRoomState`.
+    RoomStatePatch {
+        #[serde(rename = "roomCode")]
+        room_code: String,
+        seq: u64,
+        players: Vec<PlayerPatch>,
+        #[serde(rename = "removedPlayerIds")]
+        removed_player_ids: Vec<PlayerId>,
     },
     PromptState {
         #[serde(rename = "roomCode")]
@@ -48,6 +184,10 @@ pub enum ServerMessage {
         #[serde(rename = "roundId")]
         round_id: u64,
         prompt: String,
+        #[serde(rename = "promptMetadata")]
+        prompt_metadata: BTreeMap<String, String>,
+        #[serde(rename = "roundEndsAtMs")]
+        round_ends_at_ms: Option<u64>,
     },
     RaceProgress {
         #[serde(rename = "roomCode")]
@@ -56,6 +196,26 @@ pub enum ServerMessage {
         player_id: PlayerId,
         text: String,
     },
+    /// Every player whose progress changed during the last progress tick.
+    RaceProgressBatch {
+        #[serde(rename = "roomCode")]
+        room_code: String,
+        progress: Vec<PlayerProgress>,
+    },
+    /// The player rejoined from another connection, which replaces this
+    /// one. The socket is closed right after.
+    SessionReplaced {
+        #[serde(rename = "roomCode")]
+        room_code: String,
+    },
+    /// A disconnected player did not come back in time and has been removed
+    /// from the room.
+    PlayerLeft {
+        #[serde(rename = "roomCode")]
+        room_code: String,
+        #[serde(rename = "playerId")]
+        player_id: PlayerId,
+    },
     RoundResult {
         #[serde(rename = "roomCode")]
         room_code: String,
@@ -70,23 +230,171 @@ pub enum ServerMessage {
         #[serde(rename = "matchWinner")]
         match_winner: Option<PlayerId>,
     },
+    RoundExpired {
+        #[serde(rename = "roomCode")]
+        room_code: String,
+        #[serde(rename = "roundId")]
+        round_id: u64,
+        answer: String,
+    },
+    AttemptRejected {
+        #[serde(rename = "roomCode")]
+        room_code: String,
+        #[serde(rename = "roundId")]
+        round_id: u64,
+        feedback: String,
+        #[serde(rename = "growthAwarded")]
+        growth_awarded: f32,
+        #[serde(rename = "sizeLost")]
+        size_lost: f32,
+        #[serde(rename = "lockedUntilMs")]
+        locked_until_ms: Option<u64>,
+        eliminated: bool,
+    },
     Error {
+        code: ErrorCode,
         message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        details: Option<ErrorDetails>,
+    },
+}
+
+/// One player's latest progress inside a `RaceProgressBatch`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerProgress {
+    #[serde(rename = "playerId")]
+    pub player_id: PlayerId,
+    pub text: String,
+}
+
+impl ServerMessage {
+    /// An `Error` carrying `code` and its standard message.
+    pub fn error(code: ErrorCode) -> Self {
+        Self::Error {
+            code,
+            message: code.message().to_string(),
+            details: None,
+        }
+    }
+}
+
+/// Stable, machine-readable reason attached to every `ServerMessage::Error`.
+/// Clients should match on these rather than on the message text, which may
+/// change. Codes are never renamed once released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    InvalidMessage,
+    RoomNotFound,
+    RoomFull,
+    MatchInProgress,
+    UnknownGameMode,
+    NameTaken,
+    InvalidWinCondition,
+    InvalidTeam,
+    InvalidRejoinToken,
+    PlayerNotInRoom,
+    RoomClosed,
+    SpectatorCannotPlay,
+    EliminatedCannotPlay,
+    NotHost,
+    MatchAlreadyStarted,
+    HelloRequired,
+    UnsupportedProtocolVersion,
+    RateLimited,
+    TextTooLong,
+    MessageTooLarge,
+}
+
+impl ErrorCode {
+    pub fn message(self) -> &'static str {
+        match self {
+            Self::InvalidMessage => "Invalid message format",
+            Self::RoomNotFound => "Room not found",
+            Self::RoomFull => "Room is full",
+            Self::MatchInProgress => "Match is still in progress",
+            Self::UnknownGameMode => "Unknown game mode",
+            Self::NameTaken => "Name is already taken in this room",
+            Self::InvalidWinCondition => "Invalid win condition",
+            Self::InvalidTeam => "Team rooms need a team name of up to 24 characters",
+            Self::InvalidRejoinToken => "Invalid rejoin token",
+            Self::PlayerNotInRoom => "Player no longer in room",
+            Self::RoomClosed => "Room closed",
+            Self::SpectatorCannotPlay => "Spectators cannot play",
+            Self::EliminatedCannotPlay => "Eliminated players can only watch",
+            Self::NotHost => "Only the host can do that",
+            Self::MatchAlreadyStarted => "Match already started",
+            Self::HelloRequired => "Send hello before any other message",
+            Self::UnsupportedProtocolVersion => {
+                "Unsupported protocol version, reload the page to update"
+            }
+            Self::RateLimited => "Too many messages, slow down",
+            Self::TextTooLong => "Text is too long",
+            Self::MessageTooLarge => "Message is too large",
+        }
+    }
+}
+
+/// Extra context for an error, tagged by `kind`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum ErrorDetails {
+    /// Where a client message failed to parse.
+    Parse { line: usize, column: usize },
+    /// Protocol versions the server accepts.
+    ProtocolVersion {
+        #[serde(rename = "minSupported")]
+        min_supported: u32,
+        #[serde(rename = "maxSupported")]
+        max_supported: u32,
     },
 }
 
 #[cfg(test)]
 mod tests {
-    use super::ClientMessage;
+    use super::{
+        ClientMessage, Encoding, ErrorCode, ErrorDetails, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+        PlayerProgress, ServerMessage, decode_msgpack, encode_msgpack, negotiate,
+    };
+    use crate::game::{PlayerPatch, PlayerState, RoomState};
+    use crate::win_condition::WinConditionKind;
+    use serde::Serialize;
+    use serde::de::DeserializeOwned;
+    use std::fmt::Debug;
 
     #[test]
     fn parses_all_supported_client_messages() {
+        let hello = r#"{"type":"hello","protocolVersion":1,"capabilities":["future"]}"#;
+        assert!(serde_json::from_str::<ClientMessage>(hello).is_ok());
+
         let join = r#"{"type":"joinOrCreateRoom","playerName":"Alice","roomCode":"ABCD","gameMode":"keyboarding"}"#;
         assert!(serde_json::from_str::<ClientMessage>(join).is_ok());
 
+        let timed =
+            r#"{"type":"joinOrCreateRoom","winCondition":{"kind":"timed","durationSecs":120}}"#;
+        assert!(serde_json::from_str::<ClientMessage>(timed).is_ok());
+
+        let team = r#"{"type":"joinOrCreateRoom","roomCode":"ABCD","team":"red"}"#;
+        assert!(serde_json::from_str::<ClientMessage>(team).is_ok());
+
+        let capped = r#"{"type":"joinOrCreateRoom","maxPlayers":4}"#;
+        assert!(serde_json::from_str::<ClientMessage>(capped).is_ok());
+
         let rejoin = r#"{"type":"rejoinRoom","rejoinToken":"abc123"}"#;
         assert!(serde_json::from_str::<ClientMessage>(rejoin).is_ok());
 
+        let spectate = r#"{"type":"spectateRoom","roomCode":"ABCD"}"#;
+        assert!(serde_json::from_str::<ClientMessage>(spectate).is_ok());
+
+        let resync = r#"{"type":"requestRoomState"}"#;
+        assert!(serde_json::from_str::<ClientMessage>(resync).is_ok());
+
+        let start = r#"{"type":"startMatch"}"#;
+        assert!(serde_json::from_str::<ClientMessage>(start).is_ok());
+
+        let rematch = r#"{"type":"requestRematch"}"#;
+        assert!(serde_json::from_str::<ClientMessage>(rematch).is_ok());
+
         let update = r#"{"type":"inputUpdate","text":"hel"}"#;
         assert!(serde_json::from_str::<ClientMessage>(update).is_ok());
 
@@ -99,4 +407,188 @@ mod tests {
         let ping = r#"{"type":"ping","sentAtMs":123}"#;
         assert!(serde_json::from_str::<ClientMessage>(ping).is_err());
     }
+
+    #[test]
+    fn error_codes_serialize_to_stable_names() {
+        let expected = [
+            (ErrorCode::InvalidMessage, "invalidMessage"),
+            (ErrorCode::RoomNotFound, "roomNotFound"),
+            (ErrorCode::RoomFull, "roomFull"),
+            (ErrorCode::MatchInProgress, "matchInProgress"),
+            (ErrorCode::UnknownGameMode, "unknownGameMode"),
+            (ErrorCode::NameTaken, "nameTaken"),
+            (ErrorCode::InvalidWinCondition, "invalidWinCondition"),
+            (ErrorCode::InvalidTeam, "invalidTeam"),
+            (ErrorCode::InvalidRejoinToken, "invalidRejoinToken"),
+            (ErrorCode::PlayerNotInRoom, "playerNotInRoom"),
+            (ErrorCode::RoomClosed, "roomClosed"),
+            (ErrorCode::SpectatorCannotPlay, "spectatorCannotPlay"),
+            (ErrorCode::EliminatedCannotPlay, "eliminatedCannotPlay"),
+            (ErrorCode::NotHost, "notHost"),
+            (ErrorCode::MatchAlreadyStarted, "matchAlreadyStarted"),
+            (ErrorCode::HelloRequired, "helloRequired"),
+            (
+                ErrorCode::UnsupportedProtocolVersion,
+                "unsupportedProtocolVersion",
+            ),
+            (ErrorCode::RateLimited, "rateLimited"),
+            (ErrorCode::TextTooLong, "textTooLong"),
+            (ErrorCode::MessageTooLarge, "messageTooLarge"),
+        ];
+        for (code, name) in expected {
+            assert_eq!(
+                serde_json::to_value(code).expect("json"),
+                serde_json::Value::from(name)
+            );
+        }
+    }
+
+    #[test]
+    fn error_messages_include_code_and_optional_details() {
+        let plain = serde_json::to_value(ServerMessage::error(ErrorCode::RoomFull)).expect("json");
+        assert_eq!(plain["type"], "error");
+        assert_eq!(plain["code"], "roomFull");
+        assert_eq!(plain["message"], "Room is full");
+        assert!(plain.get("details").is_none());
+
+        let parse = ServerMessage::Error {
+            code: ErrorCode::InvalidMessage,
+            message: ErrorCode::InvalidMessage.message().to_string(),
+            details: Some(ErrorDetails::Parse { line: 1, column: 7 }),
+        };
+        let parse = serde_json::to_value(parse).expect("json");
+        assert_eq!(
+            parse["details"],
+            serde_json::json!({ "kind": "parse", "line": 1, "column": 7 })
+        );
+    }
+
+    #[test]
+    fn negotiation_accepts_supported_versions_only() {
+        assert_eq!(
+            negotiate(
+                PROTOCOL_VERSION,
+                &["unknownFeature".to_string(), "msgpack".to_string()]
+            ),
+            Ok(vec!["msgpack".to_string()])
+        );
+        assert_eq!(
+            Encoding::negotiated(&["msgpack".to_string()]),
+            Encoding::MessagePack
+        );
+        assert_eq!(Encoding::negotiated(&[]), Encoding::Json);
+        assert_eq!(
+            negotiate(MIN_PROTOCOL_VERSION - 1, &[]),
+            Err(ErrorCode::UnsupportedProtocolVersion)
+        );
+        assert_eq!(
+            negotiate(PROTOCOL_VERSION + 1, &[]),
+            Err(ErrorCode::UnsupportedProtocolVersion)
+        );
+    }
+
+    fn assert_both_encodings_agree<T>(message: &T)
+    where
+        T: Serialize + DeserializeOwned + PartialEq + Debug,
+    {
+        let json = serde_json::to_string(message).expect("json encode");
+        let from_json: T = serde_json::from_str(&json).expect("json decode");
+        let msgpack = encode_msgpack(message).expect("msgpack encode");
+        let from_msgpack: T = decode_msgpack(&msgpack).expect("msgpack decode");
+        assert_eq!(&from_json, message);
+        assert_eq!(from_msgpack, from_json);
+    }
+
+    #[test]
+    fn json_and_msgpack_decode_to_the_same_messages() {
+        let client_messages = [
+            ClientMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: vec!["msgpack".to_string()],
+            },
+            ClientMessage::JoinOrCreateRoom {
+                player_name: Some("Alice".to_string()),
+                room_code: None,
+                game_mode: Some("keyboarding".to_string()),
+                win_condition: Some(WinConditionKind::TargetSize { target_size: 40.0 }),
+                team: Some("red".to_string()),
+                max_players: Some(4),
+            },
+            ClientMessage::InputUpdate {
+                text: "hel".to_string(),
+            },
+            ClientMessage::StartMatch,
+        ];
+        for message in &client_messages {
+            assert_both_encodings_agree(message);
+        }
+
+        let mut room = RoomState::new(
+            "ABCD".to_string(),
+            "keyboarding".to_string(),
+            WinConditionKind::Timed { duration_secs: 90 },
+        );
+        room.add_player(PlayerState::new(
+            1,
+            "Alice".to_string(),
+            "#38bdf8".to_string(),
+            String::new(),
+        ));
+        room.add_player(PlayerState::new(
+            2,
+            "Bob".to_string(),
+            "#f472b6".to_string(),
+            String::new(),
+        ));
+        room.eliminate_player(2, Some(1));
+        let server_messages = [
+            ServerMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: vec!["msgpack".to_string()],
+            },
+            ServerMessage::RoomState {
+                room: room.to_snapshot(),
+                seq: 3,
+            },
+            ServerMessage::RoomStatePatch {
+                room_code: "ABCD".to_string(),
+                seq: 4,
+                players: vec![PlayerPatch {
+                    id: 1,
+                    size: Some(14.0),
+                    locked_until_ms: Some(None),
+                    ..PlayerPatch::default()
+                }],
+                removed_player_ids: vec![2],
+            },
+            ServerMessage::RaceProgress {
+                room_code: "ABCD".to_string(),
+                player_id: 1,
+                text: "hel".to_string(),
+            },
+            ServerMessage::PlayerLeft {
+                room_code: "ABCD".to_string(),
+                player_id: 2,
+            },
+            ServerMessage::SessionReplaced {
+                room_code: "ABCD".to_string(),
+            },
+            ServerMessage::RaceProgressBatch {
+                room_code: "ABCD".to_string(),
+                progress: vec![PlayerProgress {
+                    player_id: 1,
+                    text: "hel".to_string(),
+                }],
+            },
+            ServerMessage::error(ErrorCode::RoomFull),
+            ServerMessage::Error {
+                code: ErrorCode::InvalidMessage,
+                message: ErrorCode::InvalidMessage.message().to_string(),
+                details: Some(ErrorDetails::Parse { line: 1, column: 2 }),
+            },
+        ];
+        for message in &server_messages {
+            assert_both_encodings_agree(message);
+        }
+    }
 }
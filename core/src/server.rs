@@ -1,27 +1,69 @@
 use crate::adapter::{AdapterHandle, AdapterRegistry, build_adapter_registry};
+use crate::events::{Outbound, RoomEvent, RoomRules, prompt_state_message, reduce};
 use crate::game::{
-    DEFAULT_START_SIZE, MIN_EATABLE_SIZE, PlayerId, PlayerState, RoomState, apply_round_win,
+    DEFAULT_MAX_PLAYERS, MAX_TEAM_NAME_CHARS, MIN_EATABLE_SIZE, MIN_PLAYER_SIZE, PlayerId,
+    PlayerState, RoomSnapshot, RoomState, WrongAnswerPenalty,
 };
-use crate::protocol::{ClientMessage, ServerMessage};
-use axum::Router;
+use crate::limits::{ConnectionGuard, ConnectionLimits, Violation};
+use crate::protocol::{
+    ClientMessage, Encoding, ErrorCode, ErrorDetails, MIN_PROTOCOL_VERSION,
+    PROGRESS_BATCH_CAPABILITY, PROTOCOL_VERSION, PlayerProgress, STATE_PATCHES_CAPABILITY,
+    ServerMessage, decode_msgpack, encode_msgpack, negotiate,
+};
+use crate::store::{FileRoomStore, InMemoryRoomStore, RoomStore, StoreWriter};
+use crate::win_condition::{WinCondition, WinConditionKind};
 use axum::extract::State;
-use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade, close_code};
 use axum::response::IntoResponse;
 use axum::routing::get;
+use axum::{Json, Router};
+use futures_util::stream::SplitStream;
 use futures_util::{SinkExt, StreamExt};
 use rand::Rng;
 use rand::distr::Alphanumeric;
 use serde::Serialize;
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::net::TcpListener;
-use tokio::sync::{Mutex, mpsc};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock, mpsc, watch};
+use tokio::time::MissedTickBehavior;
 
 #[derive(Debug, Clone)]
 pub struct ServerConfig {
     pub bind_addr: String,
     pub growth_per_round_win: f32,
+    pub wrong_answer_penalty: WrongAnswerPenalty,
+    /// Players shrunk below this size by penalties are eliminated.
+    pub elimination_size: f32,
+    /// Overrides every adapter's own round duration when set.
+    pub round_duration: Option<Duration>,
+    /// Directory for persisted room state. Rooms only live in memory when
+    /// unset.
+    pub state_dir: Option<PathBuf>,
+    /// Largest room a creator may ask for, and the size of rooms created
+    /// without a `maxPlayers` request.
+    pub max_players_per_room: usize,
+    /// Per-connection rate and size limits.
+    pub limits: ConnectionLimits,
+    /// How long progress updates are collected before being broadcast
+    /// together.
+    pub progress_tick: Duration,
+    /// How often the server pings each client.
+    pub ping_interval: Duration,
+    /// Clients that send nothing, not even a pong, for this long are
+    /// disconnected. Should be a few times `ping_interval`.
+    pub ping_timeout: Duration,
+    /// How long a disconnected player keeps their seat and rejoin token
+    /// before being removed from the room.
+    pub reconnect_grace: Duration,
+    /// How long a rejoin token stays valid after it is issued. Every rejoin
+    /// issues a fresh one.
+    pub rejoin_token_ttl: Duration,
 }
 
 impl Default for ServerConfig {
@@ -29,26 +71,303 @@ impl Default for ServerConfig {
         Self {
             bind_addr: "0.0.0.0:4000".to_string(),
             growth_per_round_win: 4.0,
+            wrong_answer_penalty: WrongAnswerPenalty::None,
+            elimination_size: MIN_PLAYER_SIZE,
+            round_duration: None,
+            state_dir: None,
+            max_players_per_room: DEFAULT_MAX_PLAYERS,
+            limits: ConnectionLimits::default(),
+            progress_tick: Duration::from_millis(50),
+            ping_interval: Duration::from_secs(10),
+            ping_timeout: Duration::from_secs(30),
+            reconnect_grace: Duration::from_secs(60),
+            rejoin_token_ttl: Duration::from_secs(12 * 60 * 60),
+        }
+    }
+}
+
+impl ServerConfig {
+    fn room_rules(&self) -> RoomRules {
+        RoomRules {
+            growth_per_round_win: self.growth_per_round_win,
+            wrong_answer_penalty: self.wrong_answer_penalty,
+            elimination_size: self.elimination_size,
         }
     }
 }
 
+/// Close reason for clients that stopped reading their messages.
+const SLOW_CLIENT_REASON: &str = "Connection too slow, messages were backing up";
+/// Close reason for a socket whose player rejoined from another connection.
+const SESSION_TAKEN_OVER_REASON: &str = "Rejoined from another connection";
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Outbound half of a client socket. Messages are encoded the way the
+/// connection negotiated in its `Hello`.
+#[derive(Debug, Clone)]
+struct ClientSender {
+    tx: mpsc::Sender<Message>,
+    encoding: Encoding,
+    /// Whether the client negotiated `RoomStatePatch` updates.
+    state_patches: bool,
+    /// Whether the client negotiated `RaceProgressBatch` updates.
+    progress_batch: bool,
+    /// Identifies the socket, so a replaced socket cannot act for the
+    /// player's current one.
+    session_id: u64,
+    /// Closes the socket straight away, skipping whatever is still queued.
+    kick: Arc<watch::Sender<Option<CloseFrame>>>,
+    /// Progress messages dropped because the queue was backing up.
+    dropped_progress: Arc<AtomicU64>,
+}
+
+impl ClientSender {
+    fn new(tx: mpsc::Sender<Message>) -> (Self, watch::Receiver<Option<CloseFrame>>) {
+        let (kick, kicked) = watch::channel(None);
+        let sender = Self {
+            tx,
+            encoding: Encoding::Json,
+            state_patches: false,
+            progress_batch: false,
+            session_id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            kick: Arc::new(kick),
+            dropped_progress: Arc::new(AtomicU64::new(0)),
+        };
+        (sender, kicked)
+    }
+
+    /// Messages waiting to be written to the socket.
+    fn queued(&self) -> usize {
+        self.tx.max_capacity() - self.tx.capacity()
+    }
+
+    fn kick(&self, code: u16, reason: &str) {
+        self.kick.send_replace(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        }));
+    }
+}
+
 #[derive(Debug)]
 struct RoomConnection {
-    sender: mpsc::UnboundedSender<Message>,
+    sender: ClientSender,
+    /// Whether the client holds the room's latest state, so a patch is
+    /// enough for the next update.
+    synced: bool,
+}
+
+/// The last room state sent to a room's clients, which patches are computed
+/// against.
+struct RoomFeed {
+    seq: u64,
+    snapshot: RoomSnapshot,
+}
+
+/// A room and everything tied to it. Each room sits behind its own lock, so
+/// traffic in one room never waits on another.
+struct RoomEntry {
+    room: RoomState,
+    connections: HashMap<PlayerId, RoomConnection>,
+    feed: Option<RoomFeed>,
+    /// Players whose progress changed since the room's last progress tick.
+    pending_progress: BTreeSet<PlayerId>,
+    /// Set once the room has left `SharedState::rooms`, for anyone who
+    /// looked it up just before.
+    closed: bool,
+}
+
+type RoomHandle = Arc<Mutex<RoomEntry>>;
+
+impl RoomEntry {
+    fn new(room: RoomState) -> Self {
+        Self {
+            room,
+            connections: HashMap::new(),
+            feed: None,
+            pending_progress: BTreeSet::new(),
+            closed: false,
+        }
+    }
+
+    fn broadcast(&self, message: &ServerMessage) {
+        for conn in self.connections.values() {
+            let _ = send_server_message(&conn.sender, message);
+        }
+    }
+
+    fn send_to(&self, connection_id: PlayerId, message: &ServerMessage) -> bool {
+        self.connections
+            .get(&connection_id)
+            .is_some_and(|conn| send_server_message(&conn.sender, message).is_ok())
+    }
+
+    /// Sends the room's state to every connection: a `RoomStatePatch` to
+    /// synced clients that negotiated patches, and a full `RoomState` to
+    /// everyone else.
+    fn broadcast_state(&mut self) {
+        let snapshot = self.room.to_snapshot();
+        let (seq, changes) = match self.feed.as_mut() {
+            Some(feed) => {
+                let changes = feed.snapshot.player_changes(&snapshot);
+                if changes
+                    .as_ref()
+                    .is_some_and(|(patches, removed)| patches.is_empty() && removed.is_empty())
+                {
+                    // Nothing changed, but a connection that has not seen a
+                    // snapshot yet (e.g. one that just took over a session)
+                    // still needs the current state in full.
+                    let full = ServerMessage::RoomState {
+                        room: feed.snapshot.clone(),
+                        seq: feed.seq,
+                    };
+                    for conn in self.connections.values_mut().filter(|conn| !conn.synced) {
+                        let _ = send_server_message(&conn.sender, &full);
+                        conn.synced = true;
+                    }
+                    return;
+                }
+                feed.seq += 1;
+                feed.snapshot = snapshot.clone();
+                (feed.seq, changes)
+            }
+            None => {
+                self.feed = Some(RoomFeed {
+                    seq: 1,
+                    snapshot: snapshot.clone(),
+                });
+                (1, None)
+            }
+        };
+        let patch = changes.map(
+            |(players, removed_player_ids)| ServerMessage::RoomStatePatch {
+                room_code: self.room.room_code.clone(),
+                seq,
+                players,
+                removed_player_ids,
+            },
+        );
+        let full = ServerMessage::RoomState {
+            room: snapshot,
+            seq,
+        };
+
+        for conn in self.connections.values_mut() {
+            match &patch {
+                Some(patch) if conn.synced && conn.sender.state_patches => {
+                    let _ = send_server_message(&conn.sender, patch);
+                }
+                _ => {
+                    let _ = send_server_message(&conn.sender, &full);
+                    conn.synced = true;
+                }
+            }
+        }
+    }
+
+    /// Sends the last room state broadcast to the room in full, so the
+    /// connection can apply patches from then on.
+    fn send_full_state(&mut self, connection_id: Option<PlayerId>, sender: &ClientSender) {
+        let room = &self.room;
+        let feed = self.feed.get_or_insert_with(|| RoomFeed {
+            seq: 0,
+            snapshot: room.to_snapshot(),
+        });
+        let _ = send_server_message(
+            sender,
+            &ServerMessage::RoomState {
+                room: feed.snapshot.clone(),
+                seq: feed.seq,
+            },
+        );
+        if let Some(conn) = connection_id.and_then(|id| self.connections.get_mut(&id)) {
+            conn.synced = true;
+        }
+    }
+
+    /// Broadcasts the current progress of every player that changed since
+    /// the last tick: one `RaceProgressBatch` to clients that negotiated it,
+    /// one `RaceProgress` per player to the rest.
+    fn flush_progress(&mut self) -> bool {
+        let room_code = &self.room.room_code;
+        let progress: Vec<PlayerProgress> = std::mem::take(&mut self.pending_progress)
+            .into_iter()
+            .filter_map(|player_id| {
+                self.room
+                    .players
+                    .get(&player_id)
+                    .map(|player| PlayerProgress {
+                        player_id,
+                        text: player.progress.clone(),
+                    })
+            })
+            .collect();
+        if progress.is_empty() {
+            return false;
+        }
+
+        let singles: Vec<ServerMessage> = progress
+            .iter()
+            .map(|entry| ServerMessage::RaceProgress {
+                room_code: room_code.clone(),
+                player_id: entry.player_id,
+                text: entry.text.clone(),
+            })
+            .collect();
+        let batch = ServerMessage::RaceProgressBatch {
+            room_code: room_code.clone(),
+            progress,
+        };
+        for conn in self.connections.values() {
+            if conn.sender.progress_batch {
+                let _ = send_server_message(&conn.sender, &batch);
+            } else {
+                for message in &singles {
+                    let _ = send_server_message(&conn.sender, message);
+                }
+            }
+        }
+        true
+    }
+}
+
+/// The seat a rejoin token leads back to. `SharedState::rejoin_tokens` keys
+/// these by the token's hash, so tokens themselves are never kept.
+#[derive(Debug, Clone, PartialEq)]
+struct RejoinGrant {
+    room_code: String,
+    player_id: PlayerId,
+    expires_at_ms: u64,
 }
 
 struct SharedState {
     adapters: AdapterRegistry,
     default_game_key: String,
     config: ServerConfig,
-    rooms: Mutex<HashMap<String, RoomState>>,
-    connections: Mutex<HashMap<String, HashMap<PlayerId, RoomConnection>>>,
-    rejoin_tokens: Mutex<HashMap<String, (String, PlayerId)>>,
+    /// Live rooms by code. Only held to find, add or remove a room, and
+    /// never while waiting on a room's lock.
+    rooms: RwLock<HashMap<String, RoomHandle>>,
+    /// Taken after a room's lock when both are needed.
+    rejoin_tokens: Mutex<HashMap<String, RejoinGrant>>,
     prompt_seed: AtomicU64,
+    store: Arc<dyn RoomStore>,
+    store_writer: StoreWriter,
 }
 
 pub async fn run_server(adapters: Vec<AdapterHandle>, config: ServerConfig) -> Result<(), String> {
+    let store: Arc<dyn RoomStore> = match &config.state_dir {
+        Some(dir) => Arc::new(FileRoomStore::new(dir)?),
+        None => Arc::new(InMemoryRoomStore::default()),
+    };
+    run_server_with_store(adapters, config, store).await
+}
+
+pub async fn run_server_with_store(
+    adapters: Vec<AdapterHandle>,
+    config: ServerConfig,
+    store: Arc<dyn RoomStore>,
+) -> Result<(), String> {
     let default_game_key = adapters
         .first()
         .map(|adapter| adapter.game_key().to_string())
@@ -58,17 +377,14 @@ pub async fn run_server(adapters: Vec<AdapterHandle>, config: ServerConfig) -> R
         adapters,
         default_game_key,
         config: config.clone(),
-        rooms: Mutex::new(HashMap::new()),
-        connections: Mutex::new(HashMap::new()),
+        rooms: RwLock::new(HashMap::new()),
         rejoin_tokens: Mutex::new(HashMap::new()),
         prompt_seed: AtomicU64::new(1),
+        store_writer: StoreWriter::spawn(Arc::clone(&store)),
+        store,
     });
-
-    let app = Router::new()
-        .route("/healthz", get(health_handler))
-        .route("/readyz", get(health_handler))
-        .route("/ws", get(ws_handler))
-        .with_state(state);
+    restore_rooms(&state).await?;
+    let app = router(state);
 
     let listener = TcpListener::bind(&config.bind_addr)
         .await
@@ -79,230 +395,478 @@ pub async fn run_server(adapters: Vec<AdapterHandle>, config: ServerConfig) -> R
         .map_err(|e| format!("server error: {e}"))
 }
 
+fn router(state: Arc<SharedState>) -> Router {
+    Router::new()
+        .route("/healthz", get(health_handler))
+        .route("/readyz", get(health_handler))
+        .route("/metrics/queues", get(queue_metrics_handler))
+        .route("/ws", get(ws_handler))
+        .with_state(state)
+}
+
+/// Rehydrates rooms saved by a previous process. Every player starts out
+/// disconnected and has the reconnect grace window to come back with their
+/// rejoin token, and round and match timers are re-armed for whatever time
+/// they had left.
+async fn restore_rooms(state: &Arc<SharedState>) -> Result<(), String> {
+    let restored = state.store.load_rooms()?;
+    let now = now_ms();
+    let mut rooms = state.rooms.write().await;
+    let mut tokens = state.rejoin_tokens.lock().await;
+
+    for mut room in restored {
+        for player in room.players.values_mut() {
+            player.connected = false;
+            player.disconnected_at_ms = Some(now);
+            player.progress.clear();
+        }
+        for eliminated in room.eliminated.values_mut() {
+            eliminated.player.connected = false;
+            eliminated.player.disconnected_at_ms = Some(now);
+        }
+        // Eliminated players keep watching after a restart too.
+        let everyone = room.players.values().chain(
+            room.eliminated
+                .values()
+                .map(|eliminated| &eliminated.player),
+        );
+        for player in everyone.filter(|player| player.rejoin_token_expires_at_ms > now) {
+            tokens.insert(
+                player.rejoin_token_hash.clone(),
+                rejoin_grant(&room.room_code, player),
+            );
+        }
+        schedule_eviction(state, &room.room_code, state.config.reconnect_grace);
+
+        if room.match_winner.is_none() {
+            if let (Some(_), Some(ends_at_ms)) = (&room.prompt, room.round_ends_at_ms) {
+                let remaining = Duration::from_millis(ends_at_ms.saturating_sub(now));
+                schedule_round_expiry(state, &room.room_code, room.round_id, remaining);
+            }
+            if let Some(deadline_ms) = room.win_condition.deadline_ms(&room) {
+                schedule_match_deadline(state, &room.room_code, deadline_ms.saturating_sub(now));
+            }
+        }
+        rooms.insert(
+            room.room_code.clone(),
+            Arc::new(Mutex::new(RoomEntry::new(room))),
+        );
+    }
+    Ok(())
+}
+
+/// Looks up a live room and locks it.
+async fn lock_room(state: &SharedState, room_code: &str) -> Option<OwnedMutexGuard<RoomEntry>> {
+    let handle = state.rooms.read().await.get(room_code).cloned()?;
+    let entry = handle.lock_owned().await;
+    (!entry.closed).then_some(entry)
+}
+
+/// Queues a snapshot of the room for the store writer, so the room lock is
+/// never held across disk I/O.
+fn persist_room(state: &SharedState, room: &RoomState) {
+    state.store_writer.save(room);
+}
+
 async fn health_handler() -> impl IntoResponse {
     "ok"
 }
 
+/// Outbound queue pressure across every live room. Only totals are reported,
+/// so the endpoint does not give away which rooms exist.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct QueueMetrics {
+    rooms: usize,
+    connections: usize,
+    queued_messages: usize,
+    deepest_queue: usize,
+    dropped_progress: u64,
+}
+
+async fn queue_metrics_handler(State(state): State<Arc<SharedState>>) -> impl IntoResponse {
+    Json(queue_metrics(&state).await)
+}
+
+async fn queue_metrics(state: &SharedState) -> QueueMetrics {
+    let handles: Vec<RoomHandle> = state.rooms.read().await.values().cloned().collect();
+    let mut metrics = QueueMetrics::default();
+    for handle in handles {
+        let entry = handle.lock().await;
+        if entry.closed || entry.connections.is_empty() {
+            continue;
+        }
+        metrics.rooms += 1;
+        for conn in entry.connections.values() {
+            let queued = conn.sender.queued();
+            metrics.connections += 1;
+            metrics.queued_messages += queued;
+            metrics.deepest_queue = metrics.deepest_queue.max(queued);
+            metrics.dropped_progress += conn.sender.dropped_progress.load(Ordering::Relaxed);
+        }
+    }
+    metrics
+}
+
 async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<SharedState>>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+    // Frames up to twice the limit are read so the client can be told why
+    // they were refused; anything larger is cut off by the socket.
+    let hard_limit = state.config.limits.max_frame_bytes.saturating_mul(2);
+    ws.max_frame_size(hard_limit)
+        .max_message_size(hard_limit)
+        .on_upgrade(move |socket| handle_socket(socket, state))
 }
 
 async fn handle_socket(socket: WebSocket, state: Arc<SharedState>) {
     let (mut ws_tx, mut ws_rx) = socket.split();
-    let (client_tx, mut client_rx) = mpsc::unbounded_channel::<Message>();
+    let queue_capacity = state.config.limits.max_queued_messages.max(1);
+    let (tx, mut client_rx) = mpsc::channel::<Message>(queue_capacity);
+    let (mut client_tx, mut kicked) = ClientSender::new(tx);
 
+    let mut writer_kicked = kicked.clone();
+    // A zero period would make the ping timer panic.
+    let ping_interval = state.config.ping_interval.max(Duration::from_millis(100));
     let writer_task = tokio::spawn(async move {
-        while let Some(msg) = client_rx.recv().await {
-            if ws_tx.send(msg).await.is_err() {
+        let mut pings =
+            tokio::time::interval_at(tokio::time::Instant::now() + ping_interval, ping_interval);
+        pings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            let msg = tokio::select! {
+                msg = client_rx.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = pings.tick() => Message::Ping(now_ms().to_be_bytes().to_vec().into()),
+                Ok(()) = writer_kicked.changed() => {
+                    let frame = writer_kicked.borrow().clone();
+                    let _ = ws_tx.send(Message::Close(frame)).await;
+                    break;
+                }
+            };
+            let closing = matches!(msg, Message::Close(_));
+            if ws_tx.send(msg).await.is_err() || closing {
                 break;
             }
         }
     });
 
     let mut player_id: Option<PlayerId> = None;
+    let mut spectator_id: Option<PlayerId> = None;
     let mut room_code: Option<String> = None;
-
-    while let Some(Ok(msg)) = ws_rx.next().await {
-        let Message::Text(raw_text) = msg else {
+    // Capabilities enabled by the `Hello` handshake; `None` until it succeeds.
+    let mut capabilities: Option<Vec<String>> = None;
+    let mut guard = ConnectionGuard::new(&state.config.limits, now_ms());
+
+    while let Some(msg) = next_frame(&mut ws_rx, &mut kicked, state.config.ping_timeout).await {
+        let frame_bytes = match &msg {
+            Message::Text(raw_text) => raw_text.len(),
+            Message::Binary(raw_bytes) => raw_bytes.len(),
+            _ => 0,
+        };
+        if let Err(violation) = guard.check_frame(frame_bytes, now_ms()) {
+            if reject_violation(&client_tx, &guard, violation) {
+                break;
+            }
             continue;
+        }
+        let incoming = match msg {
+            Message::Text(raw_text) => {
+                serde_json::from_str::<ClientMessage>(&raw_text).map_err(|err| {
+                    Some(ErrorDetails::Parse {
+                        line: err.line(),
+                        column: err.column(),
+                    })
+                })
+            }
+            Message::Binary(raw_bytes) => {
+                decode_msgpack::<ClientMessage>(&raw_bytes).map_err(|_| None)
+            }
+            Message::Pong(payload) => {
+                if let (Some(pid), Some(code)) = (player_id, room_code.as_ref())
+                    && let Ok(sent_ms) = <[u8; 8]>::try_from(payload.as_ref())
+                {
+                    let latency_ms = now_ms().saturating_sub(u64::from_be_bytes(sent_ms));
+                    record_latency(&state, code, pid, latency_ms).await;
+                }
+                continue;
+            }
+            _ => continue,
         };
-
-        let incoming = match serde_json::from_str::<ClientMessage>(&raw_text) {
+        let incoming = match incoming {
             Ok(parsed) => parsed,
-            Err(_) => {
+            Err(details) => {
                 let _ = send_server_message(
                     &client_tx,
                     &ServerMessage::Error {
-                        message: "Invalid message format".to_string(),
+                        code: ErrorCode::InvalidMessage,
+                        message: ErrorCode::InvalidMessage.message().to_string(),
+                        details,
                     },
                 );
                 continue;
             }
         };
+        if let Err(violation) = guard.check_message(&incoming, now_ms()) {
+            if reject_violation(&client_tx, &guard, violation) {
+                break;
+            }
+            continue;
+        }
 
         match incoming {
+            ClientMessage::Hello {
+                protocol_version,
+                capabilities: requested,
+            } => {
+                if capabilities.is_some() {
+                    continue;
+                }
+                match negotiate(protocol_version, &requested) {
+                    Ok(enabled) => {
+                        let _ = send_server_message(
+                            &client_tx,
+                            &ServerMessage::Hello {
+                                protocol_version: PROTOCOL_VERSION,
+                                capabilities: enabled.clone(),
+                            },
+                        );
+                        client_tx.encoding = Encoding::negotiated(&enabled);
+                        client_tx.state_patches =
+                            enabled.iter().any(|c| c == STATE_PATCHES_CAPABILITY);
+                        client_tx.progress_batch =
+                            enabled.iter().any(|c| c == PROGRESS_BATCH_CAPABILITY);
+                        capabilities = Some(enabled);
+                    }
+                    Err(code) => {
+                        let _ = send_server_message(
+                            &client_tx,
+                            &ServerMessage::Error {
+                                code,
+                                message: code.message().to_string(),
+                                details: Some(ErrorDetails::ProtocolVersion {
+                                    min_supported: MIN_PROTOCOL_VERSION,
+                                    max_supported: PROTOCOL_VERSION,
+                                }),
+                            },
+                        );
+                        close_connection(&client_tx, close_code::PROTOCOL, code.message());
+                    }
+                }
+            }
+            _ if capabilities.is_none() => {
+                let _ = send_server_message(
+                    &client_tx,
+                    &ServerMessage::error(ErrorCode::HelloRequired),
+                );
+            }
             ClientMessage::JoinOrCreateRoom {
                 player_name,
                 room_code: requested_room_code,
                 game_mode,
+                win_condition,
+                team,
+                max_players,
             } => {
-                if player_id.is_some() {
+                if room_code.is_some() {
                     continue;
                 }
 
-                let result = join_or_create_room(
-                    &state,
+                let request = JoinRequest {
                     player_name,
-                    requested_room_code,
+                    room_code: requested_room_code,
                     game_mode,
-                    client_tx.clone(),
-                )
-                .await;
-
-                if let Some((code, token, assigned_player_id)) = result {
-                    player_id = Some(assigned_player_id);
-                    room_code = Some(code.clone());
+                    win_condition,
+                    team,
+                    max_players,
+                };
+                let result = join_or_create_room(&state, request, client_tx.clone()).await;
 
-                    {
-                        let mut tokens = state.rejoin_tokens.lock().await;
-                        tokens.insert(token.clone(), (code.clone(), assigned_player_id));
+                match result {
+                    Ok((code, _token, assigned_player_id)) => {
+                        player_id = Some(assigned_player_id);
+                        room_code = Some(code);
+                    }
+                    Err(error_code) => {
+                        let _ = send_server_message(&client_tx, &ServerMessage::error(error_code));
                     }
-
-                    let _ = send_server_message(
-                        &client_tx,
-                        &ServerMessage::Welcome {
-                            player_id: assigned_player_id,
-                            room_code: code.clone(),
-                            game_key: room_game_key(&state, &code)
-                                .await
-                                .unwrap_or_else(|| state.default_game_key.clone()),
-                            min_eatable_size: MIN_EATABLE_SIZE,
-                            rejoin_token: token,
-                        },
-                    );
-
-                    let _ = broadcast_room_state(&state, &code).await;
-                    let _ = ensure_prompt_for_room(&state, &code).await;
-                } else {
-                    let _ = send_server_message(
-                        &client_tx,
-                        &ServerMessage::Error {
-                            message: "Unable to join room".to_string(),
-                        },
-                    );
                 }
             }
             ClientMessage::RejoinRoom { rejoin_token } => {
-                if player_id.is_some() {
+                if room_code.is_some() {
                     continue;
                 }
 
-                let lookup = {
-                    let tokens = state.rejoin_tokens.lock().await;
-                    tokens.get(&rejoin_token).cloned()
-                };
-
-                let Some((found_code, found_pid)) = lookup else {
-                    let _ = send_server_message(
-                        &client_tx,
-                        &ServerMessage::Error {
-                            message: "Invalid rejoin token".to_string(),
-                        },
-                    );
+                match rejoin_room(&state, &rejoin_token, client_tx.clone()).await {
+                    Ok((found_code, found_pid)) => {
+                        player_id = Some(found_pid);
+                        room_code = Some(found_code);
+                    }
+                    Err(error_code) => {
+                        let _ = send_server_message(&client_tx, &ServerMessage::error(error_code));
+                    }
+                }
+            }
+            ClientMessage::SpectateRoom {
+                room_code: requested_room_code,
+            } => {
+                if room_code.is_some() {
                     continue;
-                };
-
-                let prompt_snapshot = {
-                    let mut rooms = state.rooms.lock().await;
-                    let Some(room) = rooms.get_mut(&found_code) else {
-                        let mut tokens = state.rejoin_tokens.lock().await;
-                        tokens.remove(&rejoin_token);
-                        let _ = send_server_message(
-                            &client_tx,
-                            &ServerMessage::Error {
-                                message: "Room no longer exists".to_string(),
-                            },
-                        );
-                        continue;
-                    };
-                    let Some(player) = room.players.get_mut(&found_pid) else {
-                        let mut tokens = state.rejoin_tokens.lock().await;
-                        tokens.remove(&rejoin_token);
-                        let _ = send_server_message(
-                            &client_tx,
-                            &ServerMessage::Error {
-                                message: "Player no longer in room".to_string(),
-                            },
-                        );
-                        continue;
-                    };
-                    player.connected = true;
+                }
 
-                    if room.prompt.is_empty() {
-                        None
-                    } else {
-                        Some((room.round_id, room.prompt.clone()))
+                match spectate_room(&state, &requested_room_code, client_tx.clone()).await {
+                    Ok(assigned_spectator_id) => {
+                        spectator_id = Some(assigned_spectator_id);
+                        room_code = Some(requested_room_code);
+                    }
+                    Err(error_code) => {
+                        let _ = send_server_message(&client_tx, &ServerMessage::error(error_code));
                     }
-                };
-
-                {
-                    let mut connections = state.connections.lock().await;
-                    connections.entry(found_code.clone()).or_default().insert(
-                        found_pid,
-                        RoomConnection {
-                            sender: client_tx.clone(),
-                        },
-                    );
                 }
-
-                player_id = Some(found_pid);
-                room_code = Some(found_code.clone());
-
+            }
+            ClientMessage::RequestRoomState => {
+                if let Some(code) = room_code.as_ref() {
+                    let connection_id = player_id.or(spectator_id);
+                    send_full_room_state(&state, code, connection_id, &client_tx).await;
+                }
+            }
+            _ if spectator_id.is_some() => {
                 let _ = send_server_message(
                     &client_tx,
-                    &ServerMessage::Welcome {
-                        player_id: found_pid,
-                        room_code: found_code.clone(),
-                        game_key: room_game_key(&state, &found_code)
-                            .await
-                            .unwrap_or_else(|| state.default_game_key.clone()),
-                        min_eatable_size: MIN_EATABLE_SIZE,
-                        rejoin_token,
-                    },
+                    &ServerMessage::error(ErrorCode::SpectatorCannotPlay),
                 );
-
-                let _ = broadcast_room_state(&state, &found_code).await;
-
-                if let Some((round_id, prompt)) = prompt_snapshot {
-                    let _ = send_server_message(
-                        &client_tx,
-                        &ServerMessage::PromptState {
-                            room_code: found_code,
-                            round_id,
-                            prompt,
-                        },
-                    );
+            }
+            ClientMessage::StartMatch => {
+                if let (Some(pid), Some(code)) = (player_id, room_code.as_ref())
+                    && let Err(error_code) = start_match(&state, code, pid).await
+                {
+                    let _ = send_server_message(&client_tx, &ServerMessage::error(error_code));
+                }
+            }
+            ClientMessage::RequestRematch => {
+                if let (Some(pid), Some(code)) = (player_id, room_code.as_ref())
+                    && let Err(error_code) = request_rematch(&state, code, pid).await
+                {
+                    let _ = send_server_message(&client_tx, &ServerMessage::error(error_code));
                 }
             }
             ClientMessage::InputUpdate { text } => {
                 if let (Some(pid), Some(code)) = (player_id, room_code.as_ref()) {
-                    handle_progress_update(&state, code, pid, text).await;
+                    if is_eliminated(&state, code, pid).await {
+                        send_eliminated_error(&client_tx);
+                    } else {
+                        handle_progress_update(&state, code, pid, text).await;
+                    }
                 }
             }
             ClientMessage::SubmitAttempt { text } => {
                 if let (Some(pid), Some(code)) = (player_id, room_code.as_ref()) {
-                    handle_submission(&state, code, pid, text).await;
+                    if is_eliminated(&state, code, pid).await {
+                        send_eliminated_error(&client_tx);
+                    } else {
+                        handle_submission(&state, code, pid, text).await;
+                    }
                 }
             }
         }
     }
 
-    if let (Some(pid), Some(code)) = (player_id, room_code) {
-        disconnect_player(&state, &code, pid).await;
+    if let (Some(pid), Some(code)) = (player_id, room_code.as_ref()) {
+        disconnect_player(&state, code, pid, client_tx.session_id).await;
     }
+    if let (Some(sid), Some(code)) = (spectator_id, room_code.as_ref()) {
+        remove_connection(&state, code, sid).await;
+    }
+
+    // Let queued messages (such as a close frame) reach the socket before
+    // tearing the writer down.
+    drop(client_tx);
+    let mut writer_task = writer_task;
+    if tokio::time::timeout(Duration::from_secs(1), &mut writer_task)
+        .await
+        .is_err()
+    {
+        writer_task.abort();
+    }
+}
+
+/// Waits for the client's next frame. Returns `None` once the socket ends,
+/// the connection has been kicked, or the client has been silent for
+/// `timeout`.
+async fn next_frame(
+    ws_rx: &mut SplitStream<WebSocket>,
+    kicked: &mut watch::Receiver<Option<CloseFrame>>,
+    timeout: Duration,
+) -> Option<Message> {
+    tokio::select! {
+        frame = ws_rx.next() => frame.and_then(Result::ok),
+        Ok(()) = kicked.changed() => None,
+        _ = tokio::time::sleep(timeout) => None,
+    }
+}
+
+/// Tells the client why its message was refused. Returns true once the
+/// connection has run out of violations and has been closed.
+fn reject_violation(
+    client_tx: &ClientSender,
+    guard: &ConnectionGuard,
+    violation: Violation,
+) -> bool {
+    let code = match violation {
+        Violation::RateLimited => ErrorCode::RateLimited,
+        Violation::TextTooLong => ErrorCode::TextTooLong,
+        Violation::FrameTooLarge => ErrorCode::MessageTooLarge,
+    };
+    let _ = send_server_message(client_tx, &ServerMessage::error(code));
+    if !guard.exhausted() {
+        return false;
+    }
+    let close = if violation == Violation::FrameTooLarge {
+        close_code::SIZE
+    } else {
+        close_code::POLICY
+    };
+    close_connection(client_tx, close, code.message());
+    true
+}
 
-    writer_task.abort();
+/// Fields of a `JoinOrCreateRoom` request.
+#[derive(Debug, Default)]
+struct JoinRequest {
+    player_name: Option<String>,
+    room_code: Option<String>,
+    game_mode: Option<String>,
+    win_condition: Option<WinConditionKind>,
+    team: Option<String>,
+    max_players: Option<usize>,
 }
 
 async fn join_or_create_room(
     state: &Arc<SharedState>,
-    player_name: Option<String>,
-    requested_room_code: Option<String>,
-    requested_game_mode: Option<String>,
-    sender: mpsc::UnboundedSender<Message>,
-) -> Option<(String, String, PlayerId)> {
+    request: JoinRequest,
+    sender: ClientSender,
+) -> Result<(String, String, PlayerId), ErrorCode> {
+    let team = match request.team.as_deref().map(str::trim) {
+        Some(team) if team.is_empty() || team.chars().count() > MAX_TEAM_NAME_CHARS => {
+            return Err(ErrorCode::InvalidTeam);
+        }
+        team => team.map(str::to_string),
+    };
     let token = generate_rejoin_token();
-    let mut rooms = state.rooms.lock().await;
-    let mut connections = state.connections.lock().await;
 
-    let room_code = match requested_room_code {
-        Some(code) if rooms.contains_key(&code) => code,
-        Some(_) => return None,
+    let handle = match request.room_code {
+        Some(code) => state
+            .rooms
+            .read()
+            .await
+            .get(&code)
+            .cloned()
+            .ok_or(ErrorCode::RoomNotFound)?,
         None => {
-            let requested = requested_game_mode
+            let requested = request
+                .game_mode
                 .as_deref()
                 .map(str::trim)
                 .filter(|s| !s.is_empty());
@@ -311,273 +875,623 @@ async fn join_or_create_room(
                     if state.adapters.contains_key(game_key) {
                         game_key.to_string()
                     } else {
-                        return None;
+                        return Err(ErrorCode::UnknownGameMode);
                     }
                 }
                 None => state.default_game_key.clone(),
             };
+            let win_condition = request.win_condition.unwrap_or_default();
+            if !win_condition.is_valid() {
+                return Err(ErrorCode::InvalidWinCondition);
+            }
+            let mut rooms = state.rooms.write().await;
             let generated = generate_room_code(&rooms);
-            rooms.insert(
-                generated.clone(),
-                RoomState {
-                    room_code: generated.clone(),
-                    game_key: room_game_key,
-                    players: HashMap::new(),
-                    prompt: String::new(),
-                    round_id: 0,
-                    match_winner: None,
-                    next_player_id: 1,
-                },
-            );
-            generated
+            let mut room = RoomState::new(generated.clone(), room_game_key, win_condition);
+            room.team_mode = team.is_some();
+            room.max_players = request
+                .max_players
+                .unwrap_or(state.config.max_players_per_room)
+                .clamp(1, state.config.max_players_per_room.max(1));
+            let handle = Arc::new(Mutex::new(RoomEntry::new(room)));
+            rooms.insert(generated, Arc::clone(&handle));
+            handle
         }
     };
 
-    let room = rooms.get_mut(&room_code)?;
-
-    let player_id = room.next_player_id;
-    room.next_player_id += 1;
-
-    room.players.insert(
+    let mut entry = handle.lock().await;
+    if entry.closed {
+        return Err(ErrorCode::RoomNotFound);
+    }
+    let room_code = entry.room.room_code.clone();
+    let player_id = entry.room.next_player_id;
+    let name = request
+        .player_name
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| format!("Player-{player_id}"));
+    let player = PlayerState {
+        team,
+        rejoin_token_expires_at_ms: rejoin_token_expiry(state, now_ms()),
+        ..PlayerState::new(
+            player_id,
+            name,
+            generate_color(player_id),
+            hash_rejoin_token(&token),
+        )
+    };
+    let grant = rejoin_grant(&room_code, &player);
+    let outbound = reduce(
+        &mut entry.room,
+        RoomEvent::PlayerJoined { player },
+        &state.config.room_rules(),
+    )?;
+    persist_room(state, &entry.room);
+
+    entry.connections.insert(
         player_id,
-        PlayerState {
-            id: player_id,
-            name: player_name
-                .filter(|name| !name.trim().is_empty())
-                .unwrap_or_else(|| format!("Player-{player_id}")),
-            size: DEFAULT_START_SIZE,
-            color: generate_color(player_id),
-            connected: true,
-            progress: String::new(),
+        RoomConnection {
+            sender: sender.clone(),
+            synced: false,
+        },
+    );
+    state
+        .rejoin_tokens
+        .lock()
+        .await
+        .insert(hash_rejoin_token(&token), grant);
+    let _ = send_server_message(
+        &sender,
+        &ServerMessage::Welcome {
+            player_id,
+            room_code: room_code.clone(),
+            game_key: entry.room.game_key.clone(),
+            min_eatable_size: MIN_EATABLE_SIZE,
             rejoin_token: token.clone(),
         },
     );
-
-    connections
-        .entry(room_code.clone())
-        .or_default()
-        .insert(player_id, RoomConnection { sender });
-
-    Some((room_code, token, player_id))
+    carry_out(state, &mut entry, outbound).await;
+    Ok((room_code, token, player_id))
 }
 
-async fn handle_progress_update(
+/// Puts a player back in their seat using a rejoin token. The token is spent
+/// and a new one goes out in the `Welcome`. If the player's previous socket
+/// is still open, it is closed and the new connection takes over. Returns
+/// the room code and player id.
+async fn rejoin_room(
     state: &Arc<SharedState>,
-    room_code: &str,
-    player_id: PlayerId,
-    text: String,
-) {
-    let Some(adapter) = adapter_for_room(state, room_code).await else {
-        return;
+    rejoin_token: &str,
+    sender: ClientSender,
+) -> Result<(String, PlayerId), ErrorCode> {
+    let now = now_ms();
+    let token_hash = hash_rejoin_token(rejoin_token);
+    let grant = {
+        let mut tokens = state.rejoin_tokens.lock().await;
+        tokens.retain(|_, grant| grant.expires_at_ms > now);
+        tokens.get(&token_hash).cloned()
+    };
+    let Some(RejoinGrant {
+        room_code,
+        player_id,
+        ..
+    }) = grant
+    else {
+        return Err(ErrorCode::InvalidRejoinToken);
     };
-    let normalized = adapter.normalize_progress(&text);
 
+    let new_token = generate_rejoin_token();
+    let Some(mut entry) = lock_room(state, &room_code).await else {
+        // The room is gone for good, so the token can never work again.
+        state.rejoin_tokens.lock().await.remove(&token_hash);
+        return Err(ErrorCode::RoomNotFound);
+    };
+    let rejoin_token_hash = hash_rejoin_token(&new_token);
+    let rejoin_token_expires_at_ms = rejoin_token_expiry(state, now);
+    let event = RoomEvent::PlayerRejoined {
+        player_id,
+        rejoin_token_hash: rejoin_token_hash.clone(),
+        rejoin_token_expires_at_ms,
+    };
+    let rejoined = reduce(&mut entry.room, event, &state.config.room_rules());
     {
-        let mut rooms = state.rooms.lock().await;
-        let Some(room) = rooms.get_mut(room_code) else {
-            return;
-        };
-        let Some(player) = room.players.get_mut(&player_id) else {
-            return;
-        };
-        player.progress = normalized.clone();
+        let mut tokens = state.rejoin_tokens.lock().await;
+        tokens.remove(&token_hash);
+        if rejoined.is_ok() {
+            tokens.insert(
+                rejoin_token_hash,
+                RejoinGrant {
+                    room_code: room_code.clone(),
+                    player_id,
+                    expires_at_ms: rejoin_token_expires_at_ms,
+                },
+            );
+        }
     }
+    let outbound = rejoined?;
+    persist_room(state, &entry.room);
 
-    let _ = broadcast_to_room(
-        state,
-        room_code,
-        &ServerMessage::RaceProgress {
-            room_code: room_code.to_string(),
+    // Swapping the connection while the room is locked keeps a closing old
+    // socket from marking the player disconnected in between.
+    let replaced = entry.connections.insert(
+        player_id,
+        RoomConnection {
+            sender: sender.clone(),
+            synced: false,
+        },
+    );
+    if let Some(replaced) = replaced {
+        let _ = send_server_message(
+            &replaced.sender,
+            &ServerMessage::SessionReplaced {
+                room_code: room_code.clone(),
+            },
+        );
+        close_connection(
+            &replaced.sender,
+            close_code::POLICY,
+            SESSION_TAKEN_OVER_REASON,
+        );
+    }
+
+    let _ = send_server_message(
+        &sender,
+        &ServerMessage::Welcome {
             player_id,
-            text: normalized,
+            room_code: room_code.clone(),
+            game_key: entry.room.game_key.clone(),
+            min_eatable_size: MIN_EATABLE_SIZE,
+            rejoin_token: new_token,
         },
-    )
-    .await;
+    );
+    let prompt_snapshot = prompt_state_message(&entry.room);
+    carry_out(state, &mut entry, outbound).await;
+    if let Some(prompt_state) = prompt_snapshot {
+        let _ = send_server_message(&sender, &prompt_state);
+    }
+    Ok((room_code, player_id))
 }
 
-async fn handle_submission(
+/// Moves a room out of the lobby and deals the first prompt. Only the host
+/// may start the match.
+async fn start_match(
+    state: &Arc<SharedState>,
+    room_code: &str,
+    player_id: PlayerId,
+) -> Result<(), ErrorCode> {
+    let Some(mut entry) = lock_room(state, room_code).await else {
+        return Err(ErrorCode::RoomNotFound);
+    };
+    apply_event(state, &mut entry, RoomEvent::MatchStarted { player_id }).await?;
+    Ok(())
+}
+
+/// Starts a new match in a finished room, keeping the room code. Consumed
+/// players who are still connected are brought back into play.
+async fn request_rematch(
+    state: &Arc<SharedState>,
+    room_code: &str,
+    player_id: PlayerId,
+) -> Result<(), ErrorCode> {
+    let Some(mut entry) = lock_room(state, room_code).await else {
+        return Err(ErrorCode::RoomNotFound);
+    };
+    let event = RoomEvent::RematchRequested {
+        player_id,
+        connected_player_ids: entry.connections.keys().copied().collect(),
+    };
+    apply_event(state, &mut entry, event).await?;
+    Ok(())
+}
+
+async fn handle_progress_update(
     state: &Arc<SharedState>,
     room_code: &str,
     player_id: PlayerId,
     text: String,
 ) {
-    let Some(adapter) = adapter_for_room(state, room_code).await else {
+    let Some(mut entry) = lock_room(state, room_code).await else {
         return;
     };
-    let mut should_advance_round = false;
-    let mut round_result: Option<ServerMessage> = None;
-    let mut consumed_ids: Vec<PlayerId> = Vec::new();
+    let Some(adapter) = room_adapter(state, &entry.room) else {
+        return;
+    };
+    let event = RoomEvent::ProgressUpdated {
+        player_id,
+        text: adapter.normalize_progress(&text),
+    };
+    let _ = apply_event(state, &mut entry, event).await;
+}
 
-    {
-        let mut rooms = state.rooms.lock().await;
-        let Some(room) = rooms.get_mut(room_code) else {
-            return;
-        };
+fn schedule_progress_flush(state: &Arc<SharedState>, room_code: &str) {
+    let state = Arc::clone(state);
+    let room_code = room_code.to_string();
+    tokio::spawn(async move {
+        tokio::time::sleep(state.config.progress_tick).await;
+        flush_progress(&state, &room_code).await;
+    });
+}
 
-        if room.match_winner.is_some() || room.prompt.is_empty() {
-            return;
-        }
+async fn flush_progress(state: &Arc<SharedState>, room_code: &str) -> bool {
+    let Some(mut entry) = lock_room(state, room_code).await else {
+        return false;
+    };
+    entry.flush_progress()
+}
 
-        if !adapter.is_correct(&room.prompt, &text) {
-            return;
-        }
+async fn handle_submission(
+    state: &Arc<SharedState>,
+    room_code: &str,
+    player_id: PlayerId,
+    text: String,
+) {
+    let Some(mut entry) = lock_room(state, room_code).await else {
+        return;
+    };
+    let Some(adapter) = room_adapter(state, &entry.room) else {
+        return;
+    };
+    let Some(prompt) = entry.room.prompt.as_ref() else {
+        return;
+    };
+    let event = RoomEvent::AttemptSubmitted {
+        player_id,
+        grade: adapter.grade(prompt, &text),
+        prompt_score: adapter.score_for_prompt(prompt),
+        now_ms: now_ms(),
+    };
+    let _ = apply_event(state, &mut entry, event).await;
+}
 
-        let configured_growth = state.config.growth_per_round_win;
-        let growth = adapter
-            .score_for_prompt(&room.prompt)
-            .max(configured_growth);
-        if let Some(resolution) = apply_round_win(room, player_id, growth, MIN_EATABLE_SIZE) {
-            consumed_ids = resolution.consumed_player_ids.clone();
-            round_result = Some(ServerMessage::RoundResult {
-                room_code: room_code.to_string(),
-                round_id: room.round_id,
-                winner_player_id: resolution.round_winner,
-                growth_awarded: growth,
-                consumed_player_ids: resolution.consumed_player_ids,
-                match_winner: resolution.match_winner,
-            });
-            should_advance_round = resolution.match_winner.is_none();
-        }
-    }
+/// Runs an event through the room's rules, saves the room if it changed
+/// and carries out the result.
+async fn apply_event(
+    state: &Arc<SharedState>,
+    entry: &mut RoomEntry,
+    event: RoomEvent,
+) -> Result<bool, ErrorCode> {
+    let outbound = reduce_and_persist(state, entry, event)?;
+    let acted = !outbound.is_empty();
+    carry_out(state, entry, outbound).await;
+    Ok(acted)
+}
 
-    if !consumed_ids.is_empty() {
-        let mut tokens = state.rejoin_tokens.lock().await;
-        tokens.retain(|_, (_, pid)| !consumed_ids.contains(pid));
+/// Runs an event through the room's rules and saves the room if it changed.
+fn reduce_and_persist(
+    state: &SharedState,
+    entry: &mut RoomEntry,
+    event: RoomEvent,
+) -> Result<Vec<Outbound>, ErrorCode> {
+    let outbound = reduce(&mut entry.room, event, &state.config.room_rules())?;
+    if outbound.iter().any(|action| {
+        !matches!(
+            action,
+            Outbound::Progress(_) | Outbound::Evict(_) | Outbound::CloseRoom
+        )
+    }) {
+        persist_room(state, &entry.room);
     }
+    Ok(outbound)
+}
 
-    if let Some(msg) = round_result {
-        let _ = broadcast_to_room(state, room_code, &msg).await;
-        let _ = broadcast_room_state(state, room_code).await;
+/// Does what a room's reducer asked for, in order. Dealing the next round
+/// can ask for more, which is carried out before the rest.
+async fn carry_out(state: &Arc<SharedState>, entry: &mut RoomEntry, outbound: Vec<Outbound>) {
+    let mut queue = VecDeque::from(outbound);
+    while let Some(action) = queue.pop_front() {
+        match action {
+            Outbound::Broadcast(message) => entry.broadcast(&message),
+            Outbound::Send(player_id, message) => {
+                entry.send_to(player_id, &message);
+            }
+            Outbound::RoomState => entry.broadcast_state(),
+            Outbound::Progress(player_id) => {
+                entry.pending_progress.insert(player_id);
+                if entry.pending_progress.len() == 1 {
+                    schedule_progress_flush(state, &entry.room.room_code);
+                }
+            }
+            Outbound::NextRound => {
+                for action in deal_prompt(state, entry).into_iter().rev() {
+                    queue.push_front(action);
+                }
+            }
+            Outbound::RoundDeadline {
+                round_id,
+                ends_at_ms,
+            } => {
+                let remaining = Duration::from_millis(ends_at_ms.saturating_sub(now_ms()));
+                schedule_round_expiry(state, &entry.room.room_code, round_id, remaining);
+            }
+            Outbound::MatchDeadline(deadline_ms) => {
+                let remaining_ms = deadline_ms.saturating_sub(now_ms());
+                schedule_match_deadline(state, &entry.room.room_code, remaining_ms);
+            }
+            Outbound::Evict(player_ids) => {
+                let room_code = &entry.room.room_code;
+                state.rejoin_tokens.lock().await.retain(|_, grant| {
+                    &grant.room_code != room_code || !player_ids.contains(&grant.player_id)
+                });
+            }
+            Outbound::CloseRoom => close_room(state, entry).await,
+        }
     }
+}
 
-    if should_advance_round {
-        let _ = ensure_prompt_for_room(state, room_code).await;
-    }
+/// Deals the room's adapter's next prompt. Returns what the room asks for
+/// in turn, which is nothing unless the room is playing.
+fn deal_prompt(state: &SharedState, entry: &mut RoomEntry) -> Vec<Outbound> {
+    let Some(adapter) = room_adapter(state, &entry.room) else {
+        return Vec::new();
+    };
+    let seed = state.prompt_seed.fetch_add(1, Ordering::Relaxed);
+    let event = RoomEvent::RoundStarted {
+        prompt: adapter.next_prompt(seed),
+        now_ms: now_ms(),
+        round_duration: state
+            .config
+            .round_duration
+            .or_else(|| adapter.round_duration()),
+    };
+    reduce_and_persist(state, entry, event).unwrap_or_default()
+}
+
+fn schedule_round_expiry(
+    state: &Arc<SharedState>,
+    room_code: &str,
+    round_id: u64,
+    duration: Duration,
+) {
+    let state = Arc::clone(state);
+    let room_code = room_code.to_string();
+    tokio::spawn(async move {
+        tokio::time::sleep(duration).await;
+        expire_round(&state, &room_code, round_id).await;
+    });
 }
 
-async fn ensure_prompt_for_room(state: &Arc<SharedState>, room_code: &str) -> bool {
-    let Some(adapter) = adapter_for_room(state, room_code).await else {
+/// Ends `round_id` without a winner, revealing the answer and moving on to
+/// the next prompt. Does nothing if the round was already won or replaced.
+async fn expire_round(state: &Arc<SharedState>, room_code: &str, round_id: u64) -> bool {
+    let Some(mut entry) = lock_room(state, room_code).await else {
         return false;
     };
-    let prompt_update;
-    {
-        let mut rooms = state.rooms.lock().await;
-        let Some(room) = rooms.get_mut(room_code) else {
-            return false;
-        };
-        if room.match_winner.is_some() || room.players.is_empty() {
-            return false;
-        }
-        let seed = state.prompt_seed.fetch_add(1, Ordering::Relaxed);
-        room.round_id += 1;
-        room.prompt = adapter.next_prompt(seed);
-        for player in room.players.values_mut() {
-            player.progress.clear();
-        }
-        prompt_update = (room.round_id, room.prompt.clone());
-    }
+    let event = RoomEvent::RoundExpired {
+        round_id,
+        now_ms: now_ms(),
+    };
+    apply_event(state, &mut entry, event).await.unwrap_or(false)
+}
 
-    let (round_id, prompt) = prompt_update;
-    let _ = broadcast_to_room(
-        state,
-        room_code,
-        &ServerMessage::PromptState {
-            room_code: room_code.to_string(),
-            round_id,
-            prompt,
+fn schedule_match_deadline(state: &Arc<SharedState>, room_code: &str, delay_ms: u64) {
+    let state = Arc::clone(state);
+    let room_code = room_code.to_string();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+        end_match_at_deadline(&state, &room_code).await;
+    });
+}
+
+/// Settles a match whose win condition runs on a clock. A tie leaves the
+/// match running until the next round resolves it.
+async fn end_match_at_deadline(state: &Arc<SharedState>, room_code: &str) -> bool {
+    let Some(mut entry) = lock_room(state, room_code).await else {
+        return false;
+    };
+    let event = RoomEvent::MatchDeadlinePassed { now_ms: now_ms() };
+    apply_event(state, &mut entry, event).await.unwrap_or(false)
+}
+
+/// Registers a receive-only connection that gets every room broadcast but
+/// never becomes a player. Returns the spectator's connection id.
+async fn spectate_room(
+    state: &Arc<SharedState>,
+    room_code: &str,
+    sender: ClientSender,
+) -> Result<PlayerId, ErrorCode> {
+    let Some(mut entry) = lock_room(state, room_code).await else {
+        return Err(ErrorCode::RoomNotFound);
+    };
+    let spectator_id = entry.room.next_player_id;
+    let event = RoomEvent::SpectatorJoined { spectator_id };
+    let outbound = reduce_and_persist(state, &mut entry, event)?;
+    entry.connections.insert(
+        spectator_id,
+        RoomConnection {
+            sender,
+            synced: false,
         },
-    )
-    .await;
+    );
+    carry_out(state, &mut entry, outbound).await;
+    Ok(spectator_id)
+}
 
-    true
+async fn remove_connection(state: &Arc<SharedState>, room_code: &str, connection_id: PlayerId) {
+    if let Some(mut entry) = lock_room(state, room_code).await {
+        entry.connections.remove(&connection_id);
+    }
 }
 
-async fn disconnect_player(state: &Arc<SharedState>, room_code: &str, player_id: PlayerId) {
-    {
-        let mut connections = state.connections.lock().await;
-        if let Some(room_connections) = connections.get_mut(room_code) {
-            room_connections.remove(&player_id);
-            if room_connections.is_empty() {
-                connections.remove(room_code);
-            }
+/// Stores the round trip of a player's latest pong and shares it with the
+/// room, unless it is within `events::LATENCY_REPORT_STEP_MS` of the value the room
+/// already has.
+async fn record_latency(
+    state: &Arc<SharedState>,
+    room_code: &str,
+    player_id: PlayerId,
+    latency_ms: u64,
+) -> bool {
+    let Some(mut entry) = lock_room(state, room_code).await else {
+        return false;
+    };
+    let event = RoomEvent::LatencyMeasured {
+        player_id,
+        latency_ms,
+    };
+    // Latency is not saved with the room, so this skips `apply_event`'s
+    // persisting.
+    match reduce(&mut entry.room, event, &state.config.room_rules()) {
+        Ok(outbound) => {
+            carry_out(state, &mut entry, outbound).await;
+            true
         }
+        Err(_) => false,
     }
+}
 
-    let all_disconnected;
-    {
-        let mut rooms = state.rooms.lock().await;
-        if let Some(room) = rooms.get_mut(room_code) {
-            if let Some(player) = room.players.get_mut(&player_id) {
-                player.connected = false;
-            }
-            all_disconnected = room.players.values().all(|p| !p.connected);
-            if all_disconnected {
-                rooms.remove(room_code);
-            }
-        } else {
-            all_disconnected = true;
+/// Marks a player disconnected once `session_id`, their socket, has closed.
+/// Does nothing if the player has since rejoined on another socket.
+async fn disconnect_player(
+    state: &Arc<SharedState>,
+    room_code: &str,
+    player_id: PlayerId,
+    session_id: u64,
+) {
+    let Some(mut entry) = lock_room(state, room_code).await else {
+        return;
+    };
+    match entry.connections.get(&player_id) {
+        Some(conn) if conn.sender.session_id != session_id => return,
+        Some(_) => {
+            entry.connections.remove(&player_id);
         }
+        None => {}
     }
 
-    if all_disconnected {
-        let mut tokens = state.rejoin_tokens.lock().await;
-        tokens.retain(|_, (rc, _)| rc != room_code);
-    } else {
-        let _ = broadcast_room_state(state, room_code).await;
+    let event = RoomEvent::PlayerLeft {
+        player_id,
+        now_ms: now_ms(),
+    };
+    let _ = apply_event(state, &mut entry, event).await;
+    if !entry.closed {
+        schedule_eviction(state, room_code, state.config.reconnect_grace);
     }
 }
 
-async fn broadcast_room_state(state: &Arc<SharedState>, room_code: &str) -> bool {
-    let snapshot = {
-        let rooms = state.rooms.lock().await;
-        let Some(room) = rooms.get(room_code) else {
-            return false;
-        };
-        room.to_snapshot()
-    };
+/// Takes a room out of `rooms` and the store, expires its rejoin tokens and
+/// tells any spectators still watching that it has closed.
+async fn close_room(state: &SharedState, entry: &mut RoomEntry) {
+    entry.closed = true;
+    let room_code = &entry.room.room_code;
+    state.rooms.write().await.remove(room_code);
+    state.store_writer.remove(room_code);
+    state
+        .rejoin_tokens
+        .lock()
+        .await
+        .retain(|_, grant| &grant.room_code != room_code);
+    for (_, conn) in entry.connections.drain() {
+        let _ = send_server_message(&conn.sender, &ServerMessage::error(ErrorCode::RoomClosed));
+    }
+}
 
-    broadcast_to_room(
-        state,
-        room_code,
-        &ServerMessage::RoomState { room: snapshot },
-    )
-    .await
+fn schedule_eviction(state: &Arc<SharedState>, room_code: &str, delay: Duration) {
+    let state = Arc::clone(state);
+    let room_code = room_code.to_string();
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        evict_expired_players(&state, &room_code).await;
+    });
+}
+
+/// Removes players whose reconnect grace has run out and expires their
+/// rejoin tokens. The room hears a `PlayerLeft` for each, and a match the
+/// absent players were holding up can now be decided. Returns the evicted
+/// ids.
+async fn evict_expired_players(state: &Arc<SharedState>, room_code: &str) -> Vec<PlayerId> {
+    let grace_ms = state.config.reconnect_grace.as_millis() as u64;
+    let now = now_ms();
+    let Some(mut entry) = lock_room(state, room_code).await else {
+        return Vec::new();
+    };
+    let evicted = entry.room.expired_players(now, grace_ms);
+    let event = RoomEvent::PlayersExpired {
+        now_ms: now,
+        grace_ms,
+    };
+    let _ = apply_event(state, &mut entry, event).await;
+    if !entry.closed
+        && let Some(expires_at_ms) = entry.room.next_expiry_ms(grace_ms)
+    {
+        let delay = Duration::from_millis(expires_at_ms.saturating_sub(now));
+        schedule_eviction(state, room_code, delay);
+    }
+    evicted
 }
 
-async fn broadcast_to_room(
+async fn send_full_room_state(
     state: &Arc<SharedState>,
     room_code: &str,
-    message: &ServerMessage,
-) -> bool {
-    let connections = state.connections.lock().await;
-    let Some(room_connections) = connections.get(room_code) else {
-        return false;
+    connection_id: Option<PlayerId>,
+    sender: &ClientSender,
+) {
+    if let Some(mut entry) = lock_room(state, room_code).await {
+        entry.send_full_state(connection_id, sender);
+    }
+}
+
+/// Asks the client to close the socket after any queued messages are sent.
+fn close_connection(sender: &ClientSender, code: u16, reason: &str) {
+    let frame = Message::Close(Some(CloseFrame {
+        code,
+        reason: reason.into(),
+    }));
+    if sender.tx.try_send(frame).is_err() {
+        sender.kick(code, reason);
+    }
+}
+
+/// Queues a message for the client. Once the queue is three quarters full,
+/// progress updates are dropped since a later one supersedes them; a client
+/// whose queue is completely full is disconnected.
+fn send_server_message(sender: &ClientSender, message: &ServerMessage) -> Result<(), String> {
+    let stale_progress = matches!(
+        message,
+        ServerMessage::RaceProgress { .. } | ServerMessage::RaceProgressBatch { .. }
+    );
+    if stale_progress && sender.tx.capacity() * 4 <= sender.tx.max_capacity() {
+        sender.dropped_progress.fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    }
+
+    let frame = match sender.encoding {
+        Encoding::Json => Message::Text(
+            serde_json::to_string(message)
+                .map_err(|e| format!("encode error: {e}"))?
+                .into(),
+        ),
+        Encoding::MessagePack => Message::Binary(encode_msgpack(message)?.into()),
     };
+    match sender.tx.try_send(frame) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(_)) => {
+            sender.kick(close_code::POLICY, SLOW_CLIENT_REASON);
+            Err("send error: queue full".to_string())
+        }
+        Err(err) => Err(format!("send error: {err}")),
+    }
+}
 
-    room_connections
-        .values()
-        .for_each(|conn| drop(send_server_message(&conn.sender, message)));
-    true
+async fn is_eliminated(state: &Arc<SharedState>, room_code: &str, player_id: PlayerId) -> bool {
+    lock_room(state, room_code)
+        .await
+        .is_some_and(|entry| entry.room.eliminated.contains_key(&player_id))
 }
 
-fn send_server_message<T: Serialize>(
-    sender: &mpsc::UnboundedSender<Message>,
-    message: &T,
-) -> Result<(), String> {
-    let encoded = serde_json::to_string(message).map_err(|e| format!("encode error: {e}"))?;
-    sender
-        .send(Message::Text(encoded.into()))
-        .map_err(|e| format!("send error: {e}"))
+fn send_eliminated_error(sender: &ClientSender) {
+    let _ = send_server_message(
+        sender,
+        &ServerMessage::error(ErrorCode::EliminatedCannotPlay),
+    );
 }
 
-async fn room_game_key(state: &Arc<SharedState>, room_code: &str) -> Option<String> {
-    let rooms = state.rooms.lock().await;
-    rooms.get(room_code).map(|room| room.game_key.clone())
+fn room_adapter(state: &SharedState, room: &RoomState) -> Option<AdapterHandle> {
+    state.adapters.get(&room.game_key).cloned()
 }
 
-async fn adapter_for_room(state: &Arc<SharedState>, room_code: &str) -> Option<AdapterHandle> {
-    let game_key = room_game_key(state, room_code).await?;
-    state.adapters.get(&game_key).cloned()
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
 }
 
 fn generate_rejoin_token() -> String {
@@ -588,7 +1502,28 @@ fn generate_rejoin_token() -> String {
         .collect()
 }
 
-fn generate_room_code(rooms: &HashMap<String, RoomState>) -> String {
+/// Hex SHA-256 of a rejoin token, the form tokens are stored in.
+fn hash_rejoin_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// When a rejoin token issued at `now_ms` should stop being accepted.
+fn rejoin_token_expiry(state: &SharedState, now_ms: u64) -> u64 {
+    now_ms.saturating_add(state.config.rejoin_token_ttl.as_millis() as u64)
+}
+
+fn rejoin_grant(room_code: &str, player: &PlayerState) -> RejoinGrant {
+    RejoinGrant {
+        room_code: room_code.to_string(),
+        player_id: player.id,
+        expires_at_ms: player.rejoin_token_expires_at_ms,
+    }
+}
+
+fn generate_room_code(rooms: &HashMap<String, RoomHandle>) -> String {
     let mut rng = rand::rng();
     loop {
         let code = (0..4)
@@ -611,7 +1546,9 @@ fn generate_color(player_id: PlayerId) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::adapter::GameAdapter;
+    use crate::adapter::{GameAdapter, Prompt};
+    use crate::game::{DEFAULT_START_SIZE, PlayerPatch, RoomPhase};
+    use crate::limits::RateLimit;
 
     #[derive(Debug)]
     struct TestAdapter {
@@ -625,24 +1562,41 @@ mod tests {
             self.key
         }
 
-        fn next_prompt(&self, seed: u64) -> String {
-            format!("{}-{seed}", self.prompt_prefix)
+        fn next_prompt(&self, seed: u64) -> Prompt {
+            let word = format!("{}-{seed}", self.prompt_prefix);
+            Prompt::new(word.clone(), word)
         }
 
-        fn is_correct(&self, prompt: &str, attempt: &str) -> bool {
-            prompt == attempt.trim()
+        fn is_correct(&self, prompt: &Prompt, attempt: &str) -> bool {
+            prompt.answer.as_str() == Some(attempt.trim())
         }
 
         fn normalize_progress(&self, raw_input: &str) -> String {
             raw_input.trim().to_string()
         }
 
-        fn score_for_prompt(&self, _prompt: &str) -> f32 {
+        fn score_for_prompt(&self, _prompt: &Prompt) -> f32 {
             self.score
         }
     }
 
+    fn client_channel(encoding: Encoding) -> (ClientSender, mpsc::Receiver<Message>) {
+        let (tx, rx) = mpsc::channel::<Message>(ConnectionLimits::default().max_queued_messages);
+        let (mut sender, _kicked) = ClientSender::new(tx);
+        sender.encoding = encoding;
+        (sender, rx)
+    }
+
+    /// Locks a live room for inspection.
+    async fn room_entry(state: &SharedState, room_code: &str) -> OwnedMutexGuard<RoomEntry> {
+        lock_room(state, room_code).await.expect("room exists")
+    }
+
     fn test_state() -> Arc<SharedState> {
+        test_state_with_config(ServerConfig::default())
+    }
+
+    fn test_state_with_config(config: ServerConfig) -> Arc<SharedState> {
         let adapters = build_adapter_registry(vec![
             Arc::new(TestAdapter {
                 key: "keyboarding",
@@ -657,66 +1611,124 @@ mod tests {
         ])
         .expect("adapter registry");
 
+        let store: Arc<dyn RoomStore> = Arc::new(InMemoryRoomStore::default());
         Arc::new(SharedState {
             adapters,
             default_game_key: "keyboarding".to_string(),
-            config: ServerConfig::default(),
-            rooms: Mutex::new(HashMap::new()),
-            connections: Mutex::new(HashMap::new()),
+            config,
+            rooms: RwLock::new(HashMap::new()),
             rejoin_tokens: Mutex::new(HashMap::new()),
             prompt_seed: AtomicU64::new(1),
+            store: store.clone(),
+            store_writer: StoreWriter::spawn(store),
         })
     }
 
+    /// Creates a room hosted by `name` and returns its code and the host's id.
+    async fn create_room(state: &Arc<SharedState>, name: &str) -> (String, PlayerId) {
+        let (sender, _receiver) = client_channel(Encoding::Json);
+        let (room_code, _token, pid) = join_or_create_room(
+            state,
+            JoinRequest {
+                player_name: Some(name.to_string()),
+                ..JoinRequest::default()
+            },
+            sender,
+        )
+        .await
+        .expect("room created");
+        (room_code, pid)
+    }
+
+    /// Seats `name` in an existing room and returns their rejoin token, their
+    /// id and the receiving end of their connection.
+    async fn join_room(
+        state: &Arc<SharedState>,
+        room_code: &str,
+        name: &str,
+    ) -> (String, PlayerId, mpsc::Receiver<Message>) {
+        let (sender, receiver) = client_channel(Encoding::Json);
+        let (_code, token, pid) = join_or_create_room(
+            state,
+            JoinRequest {
+                player_name: Some(name.to_string()),
+                room_code: Some(room_code.to_string()),
+                ..JoinRequest::default()
+            },
+            sender,
+        )
+        .await
+        .expect("joined room");
+        (token, pid, receiver)
+    }
+
+    /// Simulates a server restart: a fresh state restored from everything
+    /// `state` has persisted so far.
+    async fn restart(state: &Arc<SharedState>) -> Arc<SharedState> {
+        let restarted = test_state();
+        state.store_writer.flush().await;
+        for room in state.store.load_rooms().expect("load rooms") {
+            restarted.store.save_room(&room).expect("save room");
+        }
+        restore_rooms(&restarted).await.expect("restore");
+        restarted
+    }
+
     #[tokio::test]
     async fn creates_room_with_requested_game_mode() {
         let state = test_state();
-        let (sender, _) = mpsc::unbounded_channel::<Message>();
+        let (sender, _) = client_channel(Encoding::Json);
 
         let (room_code, _token, _pid) = join_or_create_room(
             &state,
-            Some("Alice".to_string()),
-            None,
-            Some("arithmetic".to_string()),
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                game_mode: Some("arithmetic".to_string()),
+                ..JoinRequest::default()
+            },
             sender,
         )
         .await
         .expect("room created");
 
-        let rooms = state.rooms.lock().await;
-        let room = rooms.get(&room_code).expect("room exists");
+        let entry = room_entry(&state, &room_code).await;
+        let room = &entry.room;
         assert_eq!(room.game_key, "arithmetic");
     }
 
     #[tokio::test]
     async fn rejects_unknown_game_mode_on_room_create() {
         let state = test_state();
-        let (sender, _) = mpsc::unbounded_channel::<Message>();
+        let (sender, _) = client_channel(Encoding::Json);
 
         let result = join_or_create_room(
             &state,
-            Some("Alice".to_string()),
-            None,
-            Some("unknown-mode".to_string()),
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                game_mode: Some("unknown-mode".to_string()),
+                ..JoinRequest::default()
+            },
             sender,
         )
         .await;
 
-        assert!(result.is_none());
-        assert!(state.rooms.lock().await.is_empty());
+        assert_eq!(result, Err(ErrorCode::UnknownGameMode));
+        assert!(state.rooms.read().await.is_empty());
     }
 
     #[tokio::test]
     async fn join_existing_room_ignores_requested_game_mode() {
         let state = test_state();
-        let (sender_1, _) = mpsc::unbounded_channel::<Message>();
-        let (sender_2, _) = mpsc::unbounded_channel::<Message>();
+        let (sender_1, _) = client_channel(Encoding::Json);
+        let (sender_2, _) = client_channel(Encoding::Json);
 
         let (room_code, _token, _pid) = join_or_create_room(
             &state,
-            Some("Alice".to_string()),
-            None,
-            Some("keyboarding".to_string()),
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                game_mode: Some("keyboarding".to_string()),
+                ..JoinRequest::default()
+            },
             sender_1,
         )
         .await
@@ -724,17 +1736,20 @@ mod tests {
 
         let (joined_room_code, _token, _pid) = join_or_create_room(
             &state,
-            Some("Bob".to_string()),
-            Some(room_code.clone()),
-            Some("arithmetic".to_string()),
+            JoinRequest {
+                player_name: Some("Bob".to_string()),
+                room_code: Some(room_code.clone()),
+                game_mode: Some("arithmetic".to_string()),
+                ..JoinRequest::default()
+            },
             sender_2,
         )
         .await
         .expect("joined room");
 
         assert_eq!(joined_room_code, room_code);
-        let rooms = state.rooms.lock().await;
-        let room = rooms.get(&room_code).expect("room exists");
+        let entry = room_entry(&state, &room_code).await;
+        let room = &entry.room;
         assert_eq!(room.game_key, "keyboarding");
         assert_eq!(room.players.len(), 2);
     }
@@ -742,30 +1757,1319 @@ mod tests {
     #[tokio::test]
     async fn uses_room_adapter_for_prompt_and_scoring() {
         let state = test_state();
-        let (sender, _) = mpsc::unbounded_channel::<Message>();
+        let (sender, _) = client_channel(Encoding::Json);
         let (room_code, _token, pid) = join_or_create_room(
             &state,
-            Some("Alice".to_string()),
-            None,
-            Some("arithmetic".to_string()),
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                game_mode: Some("arithmetic".to_string()),
+                ..JoinRequest::default()
+            },
             sender,
         )
         .await
         .expect("room created");
 
-        assert!(ensure_prompt_for_room(&state, &room_code).await);
+        start_match(&state, &room_code, pid)
+            .await
+            .expect("match started");
         let prompt = {
-            let rooms = state.rooms.lock().await;
-            rooms.get(&room_code).expect("room exists").prompt.clone()
+            let entry = room_entry(&state, &room_code).await;
+            entry.room.prompt.clone().expect("prompt exists")
         };
-        assert!(prompt.starts_with("math-"));
-
-        handle_submission(&state, &room_code, pid, prompt).await;
-        let rooms = state.rooms.lock().await;
-        let player = rooms
-            .get(&room_code)
-            .and_then(|room| room.players.get(&pid))
-            .expect("player exists");
+        assert!(prompt.display.starts_with("math-"));
+
+        handle_submission(&state, &room_code, pid, prompt.display).await;
+        let entry = room_entry(&state, &room_code).await;
+        let player = entry.room.players.get(&pid).expect("player exists");
         assert_eq!(player.size, DEFAULT_START_SIZE + 9.0);
     }
+
+    #[tokio::test]
+    async fn rejected_attempt_is_reported_to_submitter() {
+        let state = test_state();
+        let (sender, mut receiver) = client_channel(Encoding::Json);
+        let (room_code, _token, pid) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                game_mode: Some("keyboarding".to_string()),
+                ..JoinRequest::default()
+            },
+            sender,
+        )
+        .await
+        .expect("room created");
+        start_match(&state, &room_code, pid)
+            .await
+            .expect("match started");
+
+        handle_submission(&state, &room_code, pid, "wrong".to_string()).await;
+
+        let mut rejection = None;
+        while let Ok(Message::Text(raw)) = receiver.try_recv() {
+            let value: serde_json::Value = serde_json::from_str(&raw).expect("json message");
+            if value["type"] == "attemptRejected" {
+                rejection = Some(value);
+            }
+        }
+        let rejection = rejection.expect("attempt rejected message");
+        assert_eq!(rejection["feedback"], "Incorrect answer");
+        assert_eq!(rejection["growthAwarded"], 0.0);
+
+        let entry = room_entry(&state, &room_code).await;
+        let room = &entry.room;
+        assert_eq!(room.round_id, 1);
+        assert_eq!(room.players[&pid].size, DEFAULT_START_SIZE);
+    }
+
+    #[tokio::test]
+    async fn wrong_answers_shrink_and_eventually_eliminate() {
+        let state = test_state_with_config(ServerConfig {
+            wrong_answer_penalty: WrongAnswerPenalty::FixedShrink(3.0),
+            ..ServerConfig::default()
+        });
+        let (sender, _receiver) = client_channel(Encoding::Json);
+        let (room_code, token, pid) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                game_mode: Some("keyboarding".to_string()),
+                ..JoinRequest::default()
+            },
+            sender,
+        )
+        .await
+        .expect("room created");
+        start_match(&state, &room_code, pid)
+            .await
+            .expect("match started");
+
+        handle_submission(&state, &room_code, pid, "wrong".to_string()).await;
+        {
+            let entry = room_entry(&state, &room_code).await;
+            let player = &entry.room.players[&pid];
+            assert_eq!(player.size, DEFAULT_START_SIZE - 3.0);
+            assert_eq!(player.to_snapshot().wrong_attempts, 1);
+        }
+
+        handle_submission(&state, &room_code, pid, "wrong".to_string()).await;
+        let entry = room_entry(&state, &room_code).await;
+        assert!(!entry.room.players.contains_key(&pid));
+        assert_eq!(entry.room.eliminated[&pid].eaten_by, None);
+        assert!(
+            state
+                .rejoin_tokens
+                .lock()
+                .await
+                .contains_key(&hash_rejoin_token(&token))
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_round_reveals_answer_and_advances() {
+        let state = test_state();
+        let (sender, mut receiver) = client_channel(Encoding::Json);
+        let (room_code, _token, pid) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                game_mode: Some("keyboarding".to_string()),
+                ..JoinRequest::default()
+            },
+            sender,
+        )
+        .await
+        .expect("room created");
+        start_match(&state, &room_code, pid)
+            .await
+            .expect("match started");
+        let answer = {
+            let entry = room_entry(&state, &room_code).await;
+            entry.room.prompt.as_ref().expect("prompt").answer_text()
+        };
+
+        assert!(!expire_round(&state, &room_code, 7).await);
+        assert!(expire_round(&state, &room_code, 1).await);
+        assert!(!expire_round(&state, &room_code, 1).await);
+
+        let mut expired = None;
+        while let Ok(Message::Text(raw)) = receiver.try_recv() {
+            let value: serde_json::Value = serde_json::from_str(&raw).expect("json message");
+            if value["type"] == "roundExpired" {
+                expired = Some(value);
+            }
+        }
+        let expired = expired.expect("round expired message");
+        assert_eq!(expired["roundId"], 1);
+        assert_eq!(expired["answer"], answer);
+        assert_eq!(room_entry(&state, &room_code).await.room.round_id, 2);
+    }
+
+    #[tokio::test]
+    async fn configured_round_duration_sets_deadline() {
+        let state = test_state_with_config(ServerConfig {
+            round_duration: Some(Duration::from_secs(30)),
+            ..ServerConfig::default()
+        });
+        let (room_code, pid) = create_room(&state, "Alice").await;
+
+        let before = now_ms();
+        start_match(&state, &room_code, pid)
+            .await
+            .expect("match started");
+        let ends_at = room_entry(&state, &room_code)
+            .await
+            .room
+            .round_ends_at_ms
+            .expect("round deadline");
+        assert!(ends_at >= before + 30_000);
+    }
+
+    #[tokio::test]
+    async fn timed_match_is_settled_at_deadline() {
+        let state = test_state();
+        let (sender_1, _receiver_1) = client_channel(Encoding::Json);
+        let (room_code, _token, alice) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                win_condition: Some(WinConditionKind::Timed { duration_secs: 60 }),
+                ..JoinRequest::default()
+            },
+            sender_1,
+        )
+        .await
+        .expect("room created");
+        let (_token, _bob, _bob_rx) = join_room(&state, &room_code, "Bob").await;
+        start_match(&state, &room_code, alice)
+            .await
+            .expect("match started");
+        assert!(!end_match_at_deadline(&state, &room_code).await);
+
+        {
+            let mut entry = room_entry(&state, &room_code).await;
+            let room = &mut entry.room;
+            room.match_started_at_ms = Some(now_ms() - 61_000);
+            room.players.get_mut(&alice).expect("alice").size += 1.0;
+        }
+        assert!(end_match_at_deadline(&state, &room_code).await);
+        assert_eq!(
+            room_entry(&state, &room_code).await.room.match_winner,
+            Some(alice)
+        );
+    }
+
+    #[tokio::test]
+    async fn restores_persisted_rooms_and_rejoin_tokens() {
+        let state = test_state();
+        let (sender, _receiver) = client_channel(Encoding::Json);
+        let (room_code, token, pid) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                ..JoinRequest::default()
+            },
+            sender,
+        )
+        .await
+        .expect("room created");
+        start_match(&state, &room_code, pid)
+            .await
+            .expect("match started");
+
+        let expires_at_ms =
+            room_entry(&state, &room_code).await.room.players[&pid].rejoin_token_expires_at_ms;
+        let restarted = restart(&state).await;
+
+        let entry = room_entry(&restarted, &room_code).await;
+        let room = &entry.room;
+        assert_eq!(room.round_id, 1);
+        assert!(room.prompt.is_some());
+        assert!(!room.players[&pid].connected);
+        assert_eq!(
+            restarted
+                .rejoin_tokens
+                .lock()
+                .await
+                .get(&hash_rejoin_token(&token))
+                .map(|grant| (
+                    grant.room_code.clone(),
+                    grant.player_id,
+                    grant.expires_at_ms
+                )),
+            Some((room_code.clone(), pid, expires_at_ms))
+        );
+    }
+
+    #[tokio::test]
+    async fn eliminated_players_can_rejoin_after_a_restart() {
+        let state = test_state();
+        let (room_code, _host) = create_room(&state, "Alice").await;
+        let (token, guest, _guest_rx) = join_room(&state, &room_code, "Bob").await;
+        {
+            let mut entry = room_entry(&state, &room_code).await;
+            entry.room.eliminate_player(guest, None);
+            persist_room(&state, &entry.room);
+        }
+
+        let restarted = restart(&state).await;
+        let (sender, _receiver) = client_channel(Encoding::Json);
+        assert_eq!(
+            rejoin_room(&restarted, &token, sender).await,
+            Ok((room_code.clone(), guest))
+        );
+        let entry = room_entry(&restarted, &room_code).await;
+        assert!(entry.room.eliminated[&guest].player.connected);
+        assert!(entry.connections.contains_key(&guest));
+    }
+
+    #[tokio::test]
+    async fn restarts_do_not_revive_expired_rejoin_tokens() {
+        let state = test_state();
+        let (sender, _receiver) = client_channel(Encoding::Json);
+        let (room_code, token, pid) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                ..JoinRequest::default()
+            },
+            sender,
+        )
+        .await
+        .expect("room created");
+        {
+            let mut entry = room_entry(&state, &room_code).await;
+            entry
+                .room
+                .players
+                .get_mut(&pid)
+                .unwrap()
+                .rejoin_token_expires_at_ms = now_ms() - 1;
+            persist_room(&state, &entry.room);
+        }
+
+        let restarted = restart(&state).await;
+        assert!(
+            !restarted
+                .rejoin_tokens
+                .lock()
+                .await
+                .contains_key(&hash_rejoin_token(&token))
+        );
+    }
+
+    #[tokio::test]
+    async fn only_host_can_start_match_from_lobby() {
+        let state = test_state();
+        let (room_code, host) = create_room(&state, "Alice").await;
+        let (_token, guest, _guest_rx) = join_room(&state, &room_code, "Bob").await;
+
+        {
+            let entry = room_entry(&state, &room_code).await;
+            let snapshot = entry.room.to_snapshot();
+            assert_eq!(snapshot.phase, RoomPhase::Lobby);
+            assert_eq!(snapshot.host_id, Some(host));
+            assert_eq!(snapshot.round_id, 0);
+        }
+
+        assert!(start_match(&state, &room_code, guest).await.is_err());
+        start_match(&state, &room_code, host)
+            .await
+            .expect("match started");
+        assert!(start_match(&state, &room_code, host).await.is_err());
+
+        let entry = room_entry(&state, &room_code).await;
+        assert_eq!(entry.room.phase, RoomPhase::Playing);
+        assert_eq!(entry.room.round_id, 1);
+    }
+
+    #[tokio::test]
+    async fn rematch_reuses_room_code_and_restores_connected_players() {
+        let state = test_state();
+        let (room_code, host) = create_room(&state, "Alice").await;
+        let (guest_token, guest, _guest_rx) = join_room(&state, &room_code, "Bob").await;
+        start_match(&state, &room_code, host)
+            .await
+            .expect("match started");
+        assert!(request_rematch(&state, &room_code, host).await.is_err());
+
+        {
+            let mut entry = room_entry(&state, &room_code).await;
+            let room = &mut entry.room;
+            room.players.get_mut(&host).expect("host").size = 40.0;
+            room.eliminate_player(guest, Some(host));
+            room.match_winner = Some(host);
+            room.phase = RoomPhase::Finished;
+            assert!(deal_prompt(&state, &mut entry).is_empty());
+        }
+        assert!(request_rematch(&state, &room_code, guest).await.is_err());
+
+        request_rematch(&state, &room_code, host)
+            .await
+            .expect("rematch started");
+
+        let entry = room_entry(&state, &room_code).await;
+        let room = &entry.room;
+        assert_eq!(room.phase, RoomPhase::Playing);
+        assert_eq!(room.match_winner, None);
+        assert_eq!(room.to_snapshot().match_number, 2);
+        assert_eq!(room.players[&host].size, DEFAULT_START_SIZE);
+        assert_eq!(room.players[&guest].size, DEFAULT_START_SIZE);
+        assert_eq!(room.rounds_in_match(), 1);
+        assert_eq!(
+            state
+                .rejoin_tokens
+                .lock()
+                .await
+                .get(&hash_rejoin_token(&guest_token))
+                .map(|grant| (grant.room_code.clone(), grant.player_id)),
+            Some((room_code.clone(), guest))
+        );
+    }
+
+    #[tokio::test]
+    async fn spectators_receive_broadcasts_without_joining_players() {
+        let state = test_state();
+        let (spectator_tx, mut spectator_rx) = client_channel(Encoding::Json);
+        let (room_code, host) = create_room(&state, "Alice").await;
+
+        assert!(
+            spectate_room(&state, "NOPE", spectator_tx.clone())
+                .await
+                .is_err()
+        );
+        let spectator = spectate_room(&state, &room_code, spectator_tx)
+            .await
+            .expect("spectating");
+        assert_ne!(spectator, host);
+        start_match(&state, &room_code, host)
+            .await
+            .expect("match started");
+
+        let mut received = Vec::new();
+        while let Ok(Message::Text(raw)) = spectator_rx.try_recv() {
+            let value: serde_json::Value = serde_json::from_str(&raw).expect("json message");
+            received.push(value["type"].as_str().unwrap_or_default().to_string());
+        }
+        assert_eq!(received[0], "spectatorWelcome");
+        assert!(received.contains(&"promptState".to_string()));
+
+        let entry = room_entry(&state, &room_code).await;
+        assert_eq!(entry.room.players.len(), 1);
+        assert!(!entry.room.players.contains_key(&spectator));
+        drop(entry);
+
+        remove_connection(&state, &room_code, spectator).await;
+        let entry = room_entry(&state, &room_code).await;
+        assert!(entry.room.players[&host].connected);
+    }
+
+    #[tokio::test]
+    async fn spectators_can_request_room_state() {
+        use tokio_tungstenite::tungstenite;
+
+        let state = test_state();
+        let (room_code, _host) = create_room(&state, "Alice").await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        tokio::spawn(async move { axum::serve(listener, router(state)).await });
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+            .await
+            .expect("connected");
+        let requests = [
+            serde_json::json!({
+                "type": "hello",
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": [],
+            }),
+            serde_json::json!({ "type": "spectateRoom", "roomCode": room_code }),
+            serde_json::json!({ "type": "requestRoomState" }),
+        ];
+        for request in requests {
+            let frame = tungstenite::Message::text(request.to_string());
+            socket.send(frame).await.expect("sent");
+        }
+
+        // One snapshot arrives on joining, the other answers the request.
+        let mut snapshots = 0;
+        while snapshots < 2 {
+            let frame = tokio::time::timeout(Duration::from_secs(5), socket.next())
+                .await
+                .expect("server replied")
+                .expect("socket open")
+                .expect("frame");
+            let tungstenite::Message::Text(raw) = frame else {
+                continue;
+            };
+            match serde_json::from_str::<ServerMessage>(&raw).expect("message") {
+                ServerMessage::RoomState { room, .. } => {
+                    assert_eq!(room.room_code, room_code);
+                    snapshots += 1;
+                }
+                ServerMessage::Error { code, .. } => panic!("unexpected error {code:?}"),
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn eaten_players_stay_connected_as_spectators() {
+        let state = test_state();
+        let (host_tx, _host_rx) = client_channel(Encoding::Json);
+        let (room_code, _token, host) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                game_mode: Some("keyboarding".to_string()),
+                ..JoinRequest::default()
+            },
+            host_tx,
+        )
+        .await
+        .expect("room created");
+        let (guest_token, guest, mut guest_rx) = join_room(&state, &room_code, "Bob").await;
+        start_match(&state, &room_code, host)
+            .await
+            .expect("match started");
+
+        let answer = {
+            let mut entry = room_entry(&state, &room_code).await;
+            let room = &mut entry.room;
+            room.players.get_mut(&host).expect("host").size = 20.0;
+            room.prompt.as_ref().expect("prompt").answer_text()
+        };
+        handle_submission(&state, &room_code, host, answer).await;
+
+        {
+            let entry = room_entry(&state, &room_code).await;
+            let snapshot = entry.room.to_snapshot();
+            assert!(snapshot.players.iter().all(|player| player.id != guest));
+            let eliminated = &snapshot.eliminated[0];
+            assert_eq!(eliminated.id, guest);
+            assert_eq!(eliminated.eaten_by, Some(host));
+            assert_eq!(eliminated.eliminated_in_round, 1);
+        }
+        {
+            let entry = room_entry(&state, &room_code).await;
+            assert!(entry.connections.contains_key(&guest));
+        }
+        assert!(
+            state
+                .rejoin_tokens
+                .lock()
+                .await
+                .contains_key(&hash_rejoin_token(&guest_token))
+        );
+
+        while guest_rx.try_recv().is_ok() {}
+        handle_progress_update(&state, &room_code, host, "kbd".to_string()).await;
+        flush_progress(&state, &room_code).await;
+        let Ok(Message::Text(raw)) = guest_rx.try_recv() else {
+            panic!("eliminated player should keep receiving broadcasts");
+        };
+        let value: serde_json::Value = serde_json::from_str(&raw).expect("json message");
+        assert_eq!(value["type"], "raceProgress");
+    }
+
+    #[tokio::test]
+    async fn team_rooms_require_a_team_to_join() {
+        let state = test_state();
+        let (sender, _receiver) = client_channel(Encoding::Json);
+        let (room_code, _token, host) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                team: Some(" red ".to_string()),
+                ..JoinRequest::default()
+            },
+            sender.clone(),
+        )
+        .await
+        .expect("team room created");
+
+        let without_team = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Bob".to_string()),
+                room_code: Some(room_code.clone()),
+                ..JoinRequest::default()
+            },
+            sender.clone(),
+        )
+        .await;
+        assert_eq!(without_team, Err(ErrorCode::InvalidTeam));
+
+        let (_code, _token, teammate) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Carol".to_string()),
+                room_code: Some(room_code.clone()),
+                team: Some("red".to_string()),
+                ..JoinRequest::default()
+            },
+            sender,
+        )
+        .await
+        .expect("joined team");
+
+        let entry = room_entry(&state, &room_code).await;
+        let snapshot = entry.room.to_snapshot();
+        assert!(snapshot.team_mode);
+        assert_eq!(snapshot.teams.len(), 1);
+        assert_eq!(snapshot.teams[0].name, "red");
+        assert_eq!(snapshot.teams[0].member_ids, vec![host, teammate]);
+    }
+
+    #[tokio::test]
+    async fn join_rejections_carry_typed_reasons() {
+        let state = test_state();
+        let (sender, _receiver) = client_channel(Encoding::Json);
+        let (room_code, _token, host) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                max_players: Some(2),
+                ..JoinRequest::default()
+            },
+            sender.clone(),
+        )
+        .await
+        .expect("room created");
+        let join = |name: &str, code: &str| JoinRequest {
+            player_name: Some(name.to_string()),
+            room_code: Some(code.to_string()),
+            ..JoinRequest::default()
+        };
+
+        assert_eq!(
+            join_or_create_room(&state, join("Bob", "NOPE"), sender.clone()).await,
+            Err(ErrorCode::RoomNotFound)
+        );
+        assert_eq!(
+            join_or_create_room(&state, join(" alice ", &room_code), sender.clone()).await,
+            Err(ErrorCode::NameTaken)
+        );
+        join_or_create_room(&state, join("Bob", &room_code), sender.clone())
+            .await
+            .expect("second seat");
+        assert_eq!(
+            join_or_create_room(&state, join("Carol", &room_code), sender.clone()).await,
+            Err(ErrorCode::RoomFull)
+        );
+
+        room_entry(&state, &room_code).await.room.max_players = 3;
+        start_match(&state, &room_code, host)
+            .await
+            .expect("match started");
+        assert_eq!(
+            join_or_create_room(&state, join("Carol", &room_code), sender).await,
+            Err(ErrorCode::MatchInProgress)
+        );
+
+        let json = serde_json::to_value(ServerMessage::error(ErrorCode::RoomFull)).expect("json");
+        assert_eq!(json["type"], "error");
+        assert_eq!(json["code"], "roomFull");
+    }
+
+    #[test]
+    fn violations_send_typed_errors_then_close_the_connection() {
+        let limits = ConnectionLimits {
+            max_text_chars: 4,
+            violations: RateLimit {
+                burst: 2,
+                per_second: 0.0,
+            },
+            ..ConnectionLimits::default()
+        };
+        let mut guard = ConnectionGuard::new(&limits, 0);
+        let (sender, mut rx) = client_channel(Encoding::Json);
+        let long_update = ClientMessage::InputUpdate {
+            text: "x".repeat(5),
+        };
+
+        let violation = guard.check_message(&long_update, 0).unwrap_err();
+        assert!(!reject_violation(&sender, &guard, violation));
+        let Ok(Message::Text(raw)) = rx.try_recv() else {
+            panic!("expected an error frame");
+        };
+        let parsed: ServerMessage = serde_json::from_str(&raw).expect("server message");
+        assert_eq!(parsed, ServerMessage::error(ErrorCode::TextTooLong));
+        assert!(rx.try_recv().is_err());
+
+        let violation = guard
+            .check_frame(limits.max_frame_bytes + 1, 0)
+            .unwrap_err();
+        assert!(reject_violation(&sender, &guard, violation));
+        let Ok(Message::Text(raw)) = rx.try_recv() else {
+            panic!("expected an error frame");
+        };
+        let parsed: ServerMessage = serde_json::from_str(&raw).expect("server message");
+        assert_eq!(parsed, ServerMessage::error(ErrorCode::MessageTooLarge));
+        let Ok(Message::Close(Some(frame))) = rx.try_recv() else {
+            panic!("expected a close frame");
+        };
+        assert_eq!(frame.code, close_code::SIZE);
+    }
+
+    #[tokio::test]
+    async fn broadcasts_use_each_connections_encoding() {
+        let state = test_state();
+        let (json_tx, mut json_rx) = client_channel(Encoding::Json);
+        let (msgpack_tx, mut msgpack_rx) = client_channel(Encoding::MessagePack);
+        let (room_code, _token, host) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                ..JoinRequest::default()
+            },
+            json_tx,
+        )
+        .await
+        .expect("room created");
+        spectate_room(&state, &room_code, msgpack_tx)
+            .await
+            .expect("spectating");
+        while json_rx.try_recv().is_ok() {}
+        while msgpack_rx.try_recv().is_ok() {}
+
+        handle_progress_update(&state, &room_code, host, "he".to_string()).await;
+        flush_progress(&state, &room_code).await;
+
+        let Ok(Message::Text(json)) = json_rx.try_recv() else {
+            panic!("json client should receive a text frame");
+        };
+        let Ok(Message::Binary(msgpack)) = msgpack_rx.try_recv() else {
+            panic!("msgpack client should receive a binary frame");
+        };
+        let from_json: ServerMessage = serde_json::from_str(&json).expect("json decode");
+        let from_msgpack: ServerMessage = decode_msgpack(&msgpack).expect("msgpack decode");
+        assert_eq!(from_json, from_msgpack);
+    }
+
+    #[tokio::test]
+    async fn progress_updates_are_coalesced_per_tick() {
+        let state = test_state();
+        let (mut host_tx, mut host_rx) = client_channel(Encoding::Json);
+        host_tx.progress_batch = true;
+        let (room_code, _token, host) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                ..JoinRequest::default()
+            },
+            host_tx,
+        )
+        .await
+        .expect("room created");
+        let (_token, guest, mut guest_rx) = join_room(&state, &room_code, "Bob").await;
+        while host_rx.try_recv().is_ok() {}
+        while guest_rx.try_recv().is_ok() {}
+
+        handle_progress_update(&state, &room_code, host, "k".to_string()).await;
+        handle_progress_update(&state, &room_code, guest, "kb".to_string()).await;
+        handle_progress_update(&state, &room_code, host, "kbd".to_string()).await;
+        assert!(host_rx.try_recv().is_err());
+        assert!(flush_progress(&state, &room_code).await);
+        assert!(!flush_progress(&state, &room_code).await);
+
+        let mut host_messages = Vec::new();
+        while let Ok(Message::Text(raw)) = host_rx.try_recv() {
+            host_messages.push(serde_json::from_str::<ServerMessage>(&raw).expect("message"));
+        }
+        assert_eq!(
+            host_messages,
+            vec![ServerMessage::RaceProgressBatch {
+                room_code: room_code.clone(),
+                progress: vec![
+                    PlayerProgress {
+                        player_id: host,
+                        text: "kbd".to_string(),
+                    },
+                    PlayerProgress {
+                        player_id: guest,
+                        text: "kb".to_string(),
+                    },
+                ],
+            }]
+        );
+
+        let mut guest_messages = Vec::new();
+        while let Ok(Message::Text(raw)) = guest_rx.try_recv() {
+            guest_messages.push(serde_json::from_str::<ServerMessage>(&raw).expect("message"));
+        }
+        assert_eq!(
+            guest_messages,
+            vec![
+                ServerMessage::RaceProgress {
+                    room_code: room_code.clone(),
+                    player_id: host,
+                    text: "kbd".to_string(),
+                },
+                ServerMessage::RaceProgress {
+                    room_code: room_code.clone(),
+                    player_id: guest,
+                    text: "kb".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn full_queues_drop_progress_then_kick_the_client() {
+        let state = test_state();
+        let (tx, _rx) = mpsc::channel::<Message>(4);
+        let (sender, kicked) = ClientSender::new(tx);
+        let (room_code, _token, host) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                ..JoinRequest::default()
+            },
+            sender.clone(),
+        )
+        .await
+        .expect("room created");
+        while sender.queued() < 3 {
+            send_server_message(&sender, &ServerMessage::error(ErrorCode::RoomFull))
+                .expect("queued");
+        }
+
+        handle_progress_update(&state, &room_code, host, "kbd".to_string()).await;
+        flush_progress(&state, &room_code).await;
+        assert_eq!(sender.queued(), 3);
+        assert_eq!(sender.dropped_progress.load(Ordering::Relaxed), 1);
+        assert!(kicked.borrow().is_none());
+
+        send_server_message(&sender, &ServerMessage::error(ErrorCode::RoomFull)).expect("queued");
+        assert!(send_server_message(&sender, &ServerMessage::error(ErrorCode::RoomFull)).is_err());
+        let frame = kicked.borrow().clone().expect("kicked");
+        assert_eq!(frame.code, close_code::POLICY);
+        assert_eq!(frame.reason.as_str(), SLOW_CLIENT_REASON);
+
+        let metrics = queue_metrics(&state).await;
+        assert_eq!(metrics.rooms, 1);
+        assert_eq!(metrics.connections, 1);
+        assert_eq!(metrics.queued_messages, 4);
+        assert_eq!(metrics.deepest_queue, 4);
+        assert_eq!(metrics.dropped_progress, 1);
+    }
+
+    #[tokio::test]
+    async fn players_gone_past_the_grace_window_are_evicted() {
+        let state = test_state();
+        let mut players = Vec::new();
+        let mut receivers = Vec::new();
+        let mut room_code = None;
+        for name in ["Alice", "Bob", "Cara"] {
+            let (sender, receiver) = client_channel(Encoding::Json);
+            let session_id = sender.session_id;
+            let (code, token, pid) = join_or_create_room(
+                &state,
+                JoinRequest {
+                    player_name: Some(name.to_string()),
+                    room_code: room_code.clone(),
+                    ..JoinRequest::default()
+                },
+                sender,
+            )
+            .await
+            .expect("joined room");
+            room_code = Some(code);
+            players.push((pid, token, session_id));
+            receivers.push(receiver);
+        }
+        let room_code = room_code.expect("room code");
+        let [
+            (host, _, _),
+            (guest, guest_token, guest_session),
+            (leader, _, _),
+        ] = players.as_slice()
+        else {
+            panic!("three players");
+        };
+        {
+            let mut entry = room_entry(&state, &room_code).await;
+            let room = &mut entry.room;
+            room.phase = RoomPhase::Playing;
+            room.players.get_mut(leader).unwrap().size = 25.0;
+        }
+
+        disconnect_player(&state, &room_code, *guest, *guest_session).await;
+        assert!(evict_expired_players(&state, &room_code).await.is_empty());
+        {
+            let mut entry = room_entry(&state, &room_code).await;
+            let room = &mut entry.room;
+            assert_eq!(room.match_winner, None);
+            let absent = room.players.get_mut(guest).unwrap();
+            absent.disconnected_at_ms = absent.disconnected_at_ms.map(|at| at - 61_000);
+        }
+        while receivers[0].try_recv().is_ok() {}
+
+        assert_eq!(
+            evict_expired_players(&state, &room_code).await,
+            vec![*guest]
+        );
+        let Ok(Message::Text(raw)) = receivers[0].try_recv() else {
+            panic!("remaining players should hear who left");
+        };
+        assert_eq!(
+            serde_json::from_str::<ServerMessage>(&raw).expect("server message"),
+            ServerMessage::PlayerLeft {
+                room_code: room_code.clone(),
+                player_id: *guest,
+            }
+        );
+        assert!(
+            !state
+                .rejoin_tokens
+                .lock()
+                .await
+                .contains_key(&hash_rejoin_token(guest_token))
+        );
+        let entry = room_entry(&state, &room_code).await;
+        let room = &entry.room;
+        assert!(!room.players.contains_key(guest));
+        assert_eq!(room.host_id, Some(*host));
+        assert_eq!(room.match_winner, Some(*leader));
+    }
+
+    #[tokio::test]
+    async fn a_lone_player_can_rejoin_within_the_grace_window() {
+        let state = test_state();
+        let (sender, _receiver) = client_channel(Encoding::Json);
+        let session_id = sender.session_id;
+        let (room_code, token, pid) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                ..JoinRequest::default()
+            },
+            sender,
+        )
+        .await
+        .expect("room created");
+
+        disconnect_player(&state, &room_code, pid, session_id).await;
+        assert!(evict_expired_players(&state, &room_code).await.is_empty());
+        let (sender, _receiver) = client_channel(Encoding::Json);
+        assert_eq!(
+            rejoin_room(&state, &token, sender).await,
+            Ok((room_code.clone(), pid))
+        );
+        assert!(room_entry(&state, &room_code).await.room.players[&pid].connected);
+    }
+
+    #[tokio::test]
+    async fn an_abandoned_room_closes_once_the_grace_window_passes() {
+        let state = test_state();
+        let (sender, _receiver) = client_channel(Encoding::Json);
+        let session_id = sender.session_id;
+        let (room_code, token, pid) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                ..JoinRequest::default()
+            },
+            sender,
+        )
+        .await
+        .expect("room created");
+
+        disconnect_player(&state, &room_code, pid, session_id).await;
+        {
+            let mut entry = room_entry(&state, &room_code).await;
+            let absent = entry.room.players.get_mut(&pid).unwrap();
+            absent.disconnected_at_ms = absent.disconnected_at_ms.map(|at| at - 61_000);
+        }
+        assert_eq!(evict_expired_players(&state, &room_code).await, vec![pid]);
+        assert!(lock_room(&state, &room_code).await.is_none());
+        assert!(
+            !state
+                .rejoin_tokens
+                .lock()
+                .await
+                .contains_key(&hash_rejoin_token(&token))
+        );
+    }
+
+    fn welcome_token(receiver: &mut mpsc::Receiver<Message>) -> String {
+        while let Ok(Message::Text(raw)) = receiver.try_recv() {
+            if let Ok(ServerMessage::Welcome { rejoin_token, .. }) = serde_json::from_str(&raw) {
+                return rejoin_token;
+            }
+        }
+        panic!("expected a welcome");
+    }
+
+    #[tokio::test]
+    async fn rejoining_rotates_the_token_and_takes_over_the_old_socket() {
+        let state = test_state();
+        let (first_tx, mut first_rx) = client_channel(Encoding::Json);
+        let (room_code, token, pid) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                ..JoinRequest::default()
+            },
+            first_tx,
+        )
+        .await
+        .expect("room created");
+        assert!(!state.rejoin_tokens.lock().await.contains_key(&token));
+        while first_rx.try_recv().is_ok() {}
+
+        let (second_tx, mut second_rx) = client_channel(Encoding::Json);
+        assert_eq!(
+            rejoin_room(&state, &token, second_tx).await,
+            Ok((room_code.clone(), pid))
+        );
+        assert!(matches!(first_rx.try_recv(), Ok(Message::Text(_))));
+        let Ok(Message::Close(Some(frame))) = first_rx.try_recv() else {
+            panic!("the replaced socket should be closed");
+        };
+        assert_eq!(frame.code, close_code::POLICY);
+        assert_eq!(frame.reason.as_str(), SESSION_TAKEN_OVER_REASON);
+
+        let rotated = welcome_token(&mut second_rx);
+        assert_ne!(rotated, token);
+        let (third_tx, _third_rx) = client_channel(Encoding::Json);
+        assert_eq!(
+            rejoin_room(&state, &token, third_tx.clone()).await,
+            Err(ErrorCode::InvalidRejoinToken)
+        );
+        {
+            let entry = room_entry(&state, &room_code).await;
+            assert_eq!(
+                entry.room.players[&pid].rejoin_token_hash,
+                hash_rejoin_token(&rotated)
+            );
+        }
+        assert_eq!(
+            rejoin_room(&state, &rotated, third_tx).await,
+            Ok((room_code, pid))
+        );
+    }
+
+    #[tokio::test]
+    async fn replaced_socket_closing_leaves_the_new_session_connected() {
+        let state = test_state();
+        let (old_tx, mut old_rx) = client_channel(Encoding::Json);
+        let old_session = old_tx.session_id;
+        let (room_code, _host) = create_room(&state, "Alice").await;
+        let (_, token, guest) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Bob".to_string()),
+                room_code: Some(room_code.clone()),
+                ..JoinRequest::default()
+            },
+            old_tx,
+        )
+        .await
+        .expect("joined room");
+        while old_rx.try_recv().is_ok() {}
+
+        let (new_tx, _new_rx) = client_channel(Encoding::Json);
+        let new_session = new_tx.session_id;
+        rejoin_room(&state, &token, new_tx).await.expect("rejoined");
+        let Ok(Message::Text(raw)) = old_rx.try_recv() else {
+            panic!("the old socket should hear it was replaced");
+        };
+        assert_eq!(
+            serde_json::from_str::<ServerMessage>(&raw).expect("server message"),
+            ServerMessage::SessionReplaced {
+                room_code: room_code.clone(),
+            }
+        );
+
+        // The old socket's handler only notices it is done afterwards.
+        disconnect_player(&state, &room_code, guest, old_session).await;
+        {
+            let entry = room_entry(&state, &room_code).await;
+            assert!(entry.room.players[&guest].connected);
+            assert_eq!(entry.connections[&guest].sender.session_id, new_session);
+        }
+
+        disconnect_player(&state, &room_code, guest, new_session).await;
+        let entry = room_entry(&state, &room_code).await;
+        assert!(!entry.room.players[&guest].connected);
+        assert!(!entry.connections.contains_key(&guest));
+    }
+
+    #[tokio::test]
+    async fn expired_rejoin_tokens_are_rejected_and_purged() {
+        let state = test_state();
+        let (sender, _receiver) = client_channel(Encoding::Json);
+        let (_room_code, token, _pid) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                ..JoinRequest::default()
+            },
+            sender.clone(),
+        )
+        .await
+        .expect("room created");
+        let token_hash = hash_rejoin_token(&token);
+        {
+            let mut tokens = state.rejoin_tokens.lock().await;
+            let grant = tokens.get_mut(&token_hash).expect("grant");
+            assert!(grant.expires_at_ms > now_ms() + 11 * 60 * 60 * 1000);
+            grant.expires_at_ms = now_ms() - 1;
+        }
+
+        assert_eq!(
+            rejoin_room(&state, &token, sender).await,
+            Err(ErrorCode::InvalidRejoinToken)
+        );
+        assert!(state.rejoin_tokens.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejoin_tokens_for_missing_rooms_are_purged() {
+        let state = test_state();
+        let (sender, _receiver) = client_channel(Encoding::Json);
+        let (room_code, token, _pid) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                ..JoinRequest::default()
+            },
+            sender.clone(),
+        )
+        .await
+        .expect("room created");
+        state.rooms.write().await.remove(&room_code);
+
+        assert_eq!(
+            rejoin_room(&state, &token, sender).await,
+            Err(ErrorCode::RoomNotFound)
+        );
+        assert!(state.rejoin_tokens.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pong_latency_is_shared_until_the_player_disconnects() {
+        let state = test_state();
+        let (mut host_tx, mut host_rx) = client_channel(Encoding::Json);
+        host_tx.state_patches = true;
+        let (guest_tx, _guest_rx) = client_channel(Encoding::Json);
+        let guest_session = guest_tx.session_id;
+        let (room_code, _token, host) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                ..JoinRequest::default()
+            },
+            host_tx,
+        )
+        .await
+        .expect("room created");
+        let (_, _token, guest) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Bob".to_string()),
+                room_code: Some(room_code.clone()),
+                ..JoinRequest::default()
+            },
+            guest_tx,
+        )
+        .await
+        .expect("joined room");
+        while host_rx.try_recv().is_ok() {}
+
+        assert!(record_latency(&state, &room_code, guest, 42).await);
+        let Ok(Message::Text(raw)) = host_rx.try_recv() else {
+            panic!("host should be told about the new latency");
+        };
+        let ServerMessage::RoomStatePatch { players, .. } =
+            serde_json::from_str(&raw).expect("server message")
+        else {
+            panic!("expected a patch");
+        };
+        assert_eq!(
+            players,
+            vec![PlayerPatch {
+                id: guest,
+                latency_ms: Some(Some(42)),
+                ..PlayerPatch::default()
+            }]
+        );
+
+        // Jitter below the reporting step is not worth a broadcast.
+        assert!(record_latency(&state, &room_code, guest, 50).await);
+        assert!(host_rx.try_recv().is_err());
+        assert!(record_latency(&state, &room_code, guest, 90).await);
+        assert!(host_rx.try_recv().is_ok());
+
+        disconnect_player(&state, &room_code, guest, guest_session).await;
+        {
+            let entry = room_entry(&state, &room_code).await;
+            let snapshot = entry.room.to_snapshot();
+            let guest_snapshot = snapshot.players.iter().find(|p| p.id == guest).unwrap();
+            assert!(!guest_snapshot.connected);
+            assert_eq!(guest_snapshot.latency_ms, None);
+        }
+        assert!(!record_latency(&state, &room_code, host + 100, 5).await);
+    }
+
+    #[tokio::test]
+    async fn patch_clients_get_a_full_snapshot_then_numbered_patches() {
+        let state = test_state();
+        let (mut host_tx, mut host_rx) = client_channel(Encoding::Json);
+        host_tx.state_patches = true;
+        let (room_code, _token, host) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                ..JoinRequest::default()
+            },
+            host_tx.clone(),
+        )
+        .await
+        .expect("room created");
+        let (_token, _guest, mut guest_rx) = join_room(&state, &room_code, "Bob").await;
+
+        let drain = |rx: &mut mpsc::Receiver<Message>| {
+            let mut received = Vec::new();
+            while let Ok(Message::Text(raw)) = rx.try_recv() {
+                match serde_json::from_str::<ServerMessage>(&raw).expect("message") {
+                    ServerMessage::Welcome { .. } => {}
+                    message => received.push(message),
+                }
+            }
+            received
+        };
+        let host_messages = drain(&mut host_rx);
+        assert!(matches!(
+            host_messages.as_slice(),
+            [
+                ServerMessage::RoomState { seq: 1, .. },
+                ServerMessage::RoomStatePatch { seq: 2, players, .. },
+            ] if players.len() == 1 && players[0].name.as_deref() == Some("Bob")
+        ));
+        assert!(matches!(
+            drain(&mut guest_rx).as_slice(),
+            [ServerMessage::RoomState { seq: 2, room }] if room.players.len() == 2
+        ));
+
+        send_full_room_state(&state, &room_code, Some(host), &host_tx).await;
+        assert!(matches!(
+            drain(&mut host_rx).as_slice(),
+            [ServerMessage::RoomState { seq: 2, .. }]
+        ));
+    }
+
+    #[tokio::test]
+    async fn taking_over_a_session_sends_the_new_socket_a_snapshot() {
+        let state = test_state();
+        let (mut old_tx, _old_rx) = client_channel(Encoding::Json);
+        old_tx.state_patches = true;
+        let (_room_code, token, _pid) = join_or_create_room(
+            &state,
+            JoinRequest {
+                player_name: Some("Alice".to_string()),
+                ..JoinRequest::default()
+            },
+            old_tx,
+        )
+        .await
+        .expect("room created");
+
+        // The player never dropped, so the takeover changes nothing in the
+        // room; the new socket still has to be brought up to date.
+        let (mut new_tx, mut new_rx) = client_channel(Encoding::Json);
+        new_tx.state_patches = true;
+        rejoin_room(&state, &token, new_tx).await.expect("rejoined");
+        let mut got_state = false;
+        while let Ok(Message::Text(raw)) = new_rx.try_recv() {
+            if let ServerMessage::RoomState { room, .. } =
+                serde_json::from_str::<ServerMessage>(&raw).expect("message")
+            {
+                got_state = room.players.len() == 1;
+            }
+        }
+        assert!(got_state);
+    }
+
+    #[tokio::test]
+    async fn a_busy_room_does_not_hold_up_other_rooms() {
+        let state = test_state();
+        let (busy_code, _) = create_room(&state, "Alice").await;
+        let (quiet_code, bob) = create_room(&state, "Bob").await;
+
+        let busy = room_entry(&state, &busy_code).await;
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            handle_progress_update(&state, &quiet_code, bob, "typing".to_string()),
+        )
+        .await
+        .expect("quiet room stays responsive");
+        drop(busy);
+
+        let quiet = room_entry(&state, &quiet_code).await;
+        assert_eq!(quiet.room.players[&bob].progress, "typing");
+        assert!(quiet.pending_progress.contains(&bob));
+    }
+
+    /// Drives keystrokes into hundreds of rooms at once. Slow, so run it on
+    /// demand with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore = "load test"]
+    async fn hundreds_of_rooms_take_keystrokes_concurrently() {
+        const ROOMS: usize = 400;
+        const PLAYERS_PER_ROOM: usize = 4;
+        const UPDATES_PER_PLAYER: usize = 25;
+
+        let state = test_state();
+        let mut seats = Vec::new();
+        for room in 0..ROOMS {
+            let (room_code, host) = create_room(&state, &format!("Host-{room}")).await;
+            seats.push((room_code.clone(), host));
+            for guest in 1..PLAYERS_PER_ROOM {
+                let (_token, pid, _receiver) =
+                    join_room(&state, &room_code, &format!("Guest-{guest}")).await;
+                seats.push((room_code.clone(), pid));
+            }
+        }
+
+        let typists: Vec<_> = seats
+            .iter()
+            .cloned()
+            .map(|(room_code, pid)| {
+                let state = Arc::clone(&state);
+                tokio::spawn(async move {
+                    for typed in 1..=UPDATES_PER_PLAYER {
+                        handle_progress_update(&state, &room_code, pid, "k".repeat(typed)).await;
+                    }
+                })
+            })
+            .collect();
+        tokio::time::timeout(Duration::from_secs(30), async {
+            for typist in typists {
+                typist.await.expect("typist finished");
+            }
+        })
+        .await
+        .expect("every update lands within the time limit");
+
+        for (room_code, pid) in &seats {
+            let entry = room_entry(&state, room_code).await;
+            assert_eq!(entry.room.players.len(), PLAYERS_PER_ROOM);
+            assert_eq!(
+                entry.room.players[pid].progress,
+                "k".repeat(UPDATES_PER_PLAYER)
+            );
+            // Whatever the progress tick has not flushed yet belongs to this
+            // room's own players.
+            assert!(
+                entry
+                    .pending_progress
+                    .iter()
+                    .all(|id| entry.room.players.contains_key(id))
+            );
+        }
+    }
 }
@@ -0,0 +1,325 @@
+use crate::game::RoomState;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, warn};
+
+/// Durable home for room state, so rooms and their rejoin tokens survive a
+/// server restart. Rejoin tokens travel inside each `PlayerState`.
+pub trait RoomStore: Send + Sync + 'static {
+    fn load_rooms(&self) -> Result<Vec<RoomState>, String>;
+    fn save_room(&self, room: &RoomState) -> Result<(), String>;
+    fn remove_room(&self, room_code: &str) -> Result<(), String>;
+}
+
+/// Default store. Keeps rooms for the lifetime of the process only.
+#[derive(Debug, Default)]
+pub struct InMemoryRoomStore {
+    rooms: Mutex<HashMap<String, RoomState>>,
+}
+
+impl RoomStore for InMemoryRoomStore {
+    fn load_rooms(&self) -> Result<Vec<RoomState>, String> {
+        let rooms = self
+            .rooms
+            .lock()
+            .map_err(|e| format!("store poisoned: {e}"))?;
+        Ok(rooms.values().cloned().collect())
+    }
+
+    fn save_room(&self, room: &RoomState) -> Result<(), String> {
+        let mut rooms = self
+            .rooms
+            .lock()
+            .map_err(|e| format!("store poisoned: {e}"))?;
+        rooms.insert(room.room_code.clone(), room.clone());
+        Ok(())
+    }
+
+    fn remove_room(&self, room_code: &str) -> Result<(), String> {
+        let mut rooms = self
+            .rooms
+            .lock()
+            .map_err(|e| format!("store poisoned: {e}"))?;
+        rooms.remove(room_code);
+        Ok(())
+    }
+}
+
+/// Stores each room as `<dir>/<ROOM_CODE>.json`.
+#[derive(Debug, Clone)]
+pub struct FileRoomStore {
+    dir: PathBuf,
+}
+
+impl FileRoomStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, String> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("failed to create state dir {}: {e}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    fn room_path(&self, room_code: &str) -> PathBuf {
+        self.dir.join(format!("{room_code}.json"))
+    }
+}
+
+impl RoomStore for FileRoomStore {
+    fn load_rooms(&self) -> Result<Vec<RoomState>, String> {
+        let entries = fs::read_dir(&self.dir)
+            .map_err(|e| format!("failed to read state dir {}: {e}", self.dir.display()))?;
+
+        let mut rooms = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(|e| format!("read error: {e}"))?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let raw = fs::read_to_string(&path)
+                .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+            // One damaged file should not keep every other room from coming
+            // back.
+            match serde_json::from_str::<RoomState>(&raw) {
+                Ok(room) => rooms.push(room),
+                Err(e) => warn!("room store: skipping {}: {e}", path.display()),
+            }
+        }
+        Ok(rooms)
+    }
+
+    fn save_room(&self, room: &RoomState) -> Result<(), String> {
+        let encoded = serde_json::to_vec(room).map_err(|e| format!("encode error: {e}"))?;
+        let path = self.room_path(&room.room_code);
+        // Write then rename so a crash mid-write never leaves a torn file.
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, encoded)
+            .map_err(|e| format!("failed to write {}: {e}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &path)
+            .map_err(|e| format!("failed to replace {}: {e}", path.display()))
+    }
+
+    fn remove_room(&self, room_code: &str) -> Result<(), String> {
+        match fs::remove_file(self.room_path(room_code)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(format!("failed to remove room {room_code}: {e}")),
+        }
+    }
+}
+
+/// Runs a store's blocking writes on a background task, one at a time and in
+/// the order they were queued, so rooms are never locked while the disk is
+/// busy. Failed writes are logged; the room stays live in memory regardless.
+#[derive(Clone)]
+pub struct StoreWriter {
+    tx: mpsc::UnboundedSender<StoreOp>,
+}
+
+enum StoreOp {
+    Save(Box<RoomState>),
+    Remove(String),
+    Flush(oneshot::Sender<()>),
+}
+
+impl StoreWriter {
+    /// Starts the writer task. Must be called from within a Tokio runtime.
+    pub fn spawn(store: Arc<dyn RoomStore>) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(op) = rx.recv().await {
+                let store = Arc::clone(&store);
+                let write = match op {
+                    StoreOp::Save(room) => tokio::task::spawn_blocking(move || {
+                        store
+                            .save_room(&room)
+                            .map_err(|e| format!("failed to save room {}: {e}", room.room_code))
+                    }),
+                    StoreOp::Remove(room_code) => tokio::task::spawn_blocking(move || {
+                        store
+                            .remove_room(&room_code)
+                            .map_err(|e| format!("failed to remove room {room_code}: {e}"))
+                    }),
+                    StoreOp::Flush(done) => {
+                        let _ = done.send(());
+                        continue;
+                    }
+                };
+                match write.await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => error!("room store: {e}"),
+                    Err(e) => error!("room store: write task failed: {e}"),
+                }
+            }
+        });
+        Self { tx }
+    }
+
+    /// Queues a snapshot of `room` to be saved.
+    pub fn save(&self, room: &RoomState) {
+        let _ = self.tx.send(StoreOp::Save(Box::new(room.clone())));
+    }
+
+    /// Queues the removal of a saved room.
+    pub fn remove(&self, room_code: &str) {
+        let _ = self.tx.send(StoreOp::Remove(room_code.to_string()));
+    }
+
+    /// Waits for every write queued so far to finish.
+    pub async fn flush(&self) {
+        let (done, finished) = oneshot::channel();
+        if self.tx.send(StoreOp::Flush(done)).is_ok() {
+            let _ = finished.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapter::Prompt;
+    use crate::game::PlayerState;
+    use crate::win_condition::WinConditionKind;
+
+    fn sample_room() -> RoomState {
+        let mut room = RoomState::new(
+            "WXYZ".to_string(),
+            "arithmetic".to_string(),
+            WinConditionKind::FixedRounds { rounds: 5 },
+        );
+        room.players.insert(
+            1,
+            PlayerState::new(
+                1,
+                "Alice".to_string(),
+                "#38bdf8".to_string(),
+                "token-1".to_string(),
+            ),
+        );
+        room.next_player_id = 2;
+        room.round_id = 3;
+        room.prompt = Some(Prompt::new("2 + 9", 11).with_metadata("operation", "addition"));
+        room
+    }
+
+    #[test]
+    fn file_store_round_trips_rooms() {
+        let dir = std::env::temp_dir().join(format!("edifio-store-{}", std::process::id()));
+        let store = FileRoomStore::new(&dir).expect("store");
+        let room = sample_room();
+
+        store.save_room(&room).expect("save");
+        let loaded = store.load_rooms().expect("load");
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].room_code, "WXYZ");
+        assert_eq!(loaded[0].round_id, 3);
+        assert_eq!(loaded[0].prompt, room.prompt);
+        assert_eq!(loaded[0].players[&1].rejoin_token_hash, "token-1");
+        assert_eq!(loaded[0].win_condition, room.win_condition);
+
+        store.remove_room("WXYZ").expect("remove");
+        assert!(store.load_rooms().expect("load").is_empty());
+        store.remove_room("WXYZ").expect("remove missing room");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn file_store_skips_rooms_it_cannot_decode() {
+        let dir = std::env::temp_dir().join(format!("edifio-store-bad-{}", std::process::id()));
+        let store = FileRoomStore::new(&dir).expect("store");
+        store.save_room(&sample_room()).expect("save");
+        fs::write(dir.join("BROKEN.json"), "{ not a room").expect("write");
+
+        let loaded = store.load_rooms().expect("load");
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].room_code, "WXYZ");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn rooms_saved_before_later_fields_existed_still_load() {
+        let mut saved = serde_json::to_value(sample_room()).expect("json");
+        let room = saved.as_object_mut().expect("room");
+        for field in [
+            "phase",
+            "host_id",
+            "eliminated",
+            "match_number",
+            "match_start_round_id",
+            "team_mode",
+            "team_sizes",
+            "max_players",
+        ] {
+            room.remove(field);
+        }
+        let player = room["players"]["1"].as_object_mut().expect("player");
+        for field in ["team", "disconnected_at_ms", "rejoin_token_expires_at_ms"] {
+            player.remove(field);
+        }
+
+        let room = serde_json::from_value::<RoomState>(saved).expect("decode");
+        assert_eq!(room.match_number, 1);
+        assert_eq!(room.max_players, crate::game::DEFAULT_MAX_PLAYERS);
+        assert!(room.eliminated.is_empty());
+        assert_eq!(room.players[&1].rejoin_token_expires_at_ms, 0);
+    }
+
+    #[test]
+    fn in_memory_store_replaces_saved_rooms() {
+        let store = InMemoryRoomStore::default();
+        let mut room = sample_room();
+        store.save_room(&room).expect("save");
+        room.round_id = 4;
+        store.save_room(&room).expect("save");
+
+        let loaded = store.load_rooms().expect("load");
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].round_id, 4);
+    }
+
+    struct FailingStore;
+
+    impl RoomStore for FailingStore {
+        fn load_rooms(&self) -> Result<Vec<RoomState>, String> {
+            Ok(Vec::new())
+        }
+
+        fn save_room(&self, _room: &RoomState) -> Result<(), String> {
+            Err("disk full".to_string())
+        }
+
+        fn remove_room(&self, _room_code: &str) -> Result<(), String> {
+            Err("disk full".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn writer_applies_queued_writes_in_order() {
+        let store = Arc::new(InMemoryRoomStore::default());
+        let writer = StoreWriter::spawn(store.clone());
+        let mut room = sample_room();
+        writer.save(&room);
+        room.round_id = 4;
+        writer.save(&room);
+        writer.flush().await;
+        let loaded = store.load_rooms().expect("load");
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].round_id, 4);
+
+        writer.remove(&room.room_code);
+        writer.flush().await;
+        assert!(store.load_rooms().expect("load").is_empty());
+    }
+
+    #[tokio::test]
+    async fn writer_keeps_going_after_a_failed_write() {
+        let writer = StoreWriter::spawn(Arc::new(FailingStore));
+        writer.save(&sample_room());
+        writer.remove("WXYZ");
+        tokio::time::timeout(std::time::Duration::from_secs(1), writer.flush())
+            .await
+            .expect("writer still running");
+    }
+}
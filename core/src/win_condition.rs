@@ -0,0 +1,272 @@
+use crate::game::{PlayerId, RoomState, evaluate_match_winner};
+use serde::{Deserialize, Serialize};
+
+/// Decides when a match is over and who won it. Evaluated after every
+/// resolved or expired round, and once more at `deadline_ms` if one is set.
+/// Strategies compare `RoomState::contenders`, so team rooms are judged team
+/// against team and the winner is the team's representative player.
+pub trait WinCondition: Send + Sync {
+    fn match_winner(&self, room: &RoomState, now_ms: u64) -> Option<PlayerId>;
+
+    /// Wall-clock time at which the match should be re-evaluated even if no
+    /// round resolves, for conditions that end on a timer.
+    fn deadline_ms(&self, _room: &RoomState) -> Option<u64> {
+        None
+    }
+}
+
+/// The original rule: with two players, the largest must be more than double
+/// the other; with more, the largest must outweigh everyone else combined.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Dominance;
+
+impl WinCondition for Dominance {
+    fn match_winner(&self, room: &RoomState, _now_ms: u64) -> Option<PlayerId> {
+        evaluate_match_winner(&room.contenders())
+    }
+}
+
+/// First player to reach `target_size` wins.
+#[derive(Debug, Clone, Copy)]
+pub struct TargetSize {
+    pub target_size: f32,
+}
+
+impl WinCondition for TargetSize {
+    fn match_winner(&self, room: &RoomState, _now_ms: u64) -> Option<PlayerId> {
+        room.contenders()
+            .into_iter()
+            .filter(|(_, size)| *size >= self.target_size)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(id, _)| id)
+    }
+}
+
+/// The last player left after everyone else has been consumed or eliminated.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LastStanding;
+
+impl WinCondition for LastStanding {
+    fn match_winner(&self, room: &RoomState, _now_ms: u64) -> Option<PlayerId> {
+        // A room that only ever had one player has nobody to outlast.
+        match room.contenders().as_slice() {
+            [(id, _)] if !room.eliminated.is_empty() => Some(*id),
+            _ => None,
+        }
+    }
+}
+
+/// After `rounds` rounds, the largest player wins. Ties play on until one
+/// player is strictly ahead.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedRounds {
+    pub rounds: u64,
+}
+
+impl WinCondition for FixedRounds {
+    fn match_winner(&self, room: &RoomState, _now_ms: u64) -> Option<PlayerId> {
+        if room.rounds_in_match() < self.rounds {
+            return None;
+        }
+        strictly_largest(&room.contenders())
+    }
+}
+
+/// When the match clock runs out, the largest player wins. Ties play on until
+/// one player is strictly ahead.
+#[derive(Debug, Clone, Copy)]
+pub struct Timed {
+    pub duration_ms: u64,
+}
+
+impl WinCondition for Timed {
+    fn match_winner(&self, room: &RoomState, now_ms: u64) -> Option<PlayerId> {
+        let deadline = self.deadline_ms(room)?;
+        if now_ms < deadline {
+            return None;
+        }
+        strictly_largest(&room.contenders())
+    }
+
+    fn deadline_ms(&self, room: &RoomState) -> Option<u64> {
+        room.match_started_at_ms
+            .map(|started| started.saturating_add(self.duration_ms))
+    }
+}
+
+fn strictly_largest(contenders: &[(PlayerId, f32)]) -> Option<PlayerId> {
+    let mut ranked = contenders.to_vec();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+    match ranked.as_slice() {
+        [(only, _)] => Some(*only),
+        [(first, first_size), (_, second_size), ..] if first_size > second_size => Some(*first),
+        _ => None,
+    }
+}
+
+/// Longest match clock a room creator may ask for.
+pub const MAX_TIMED_DURATION_SECS: u64 = 24 * 60 * 60;
+
+/// Win condition selected by the room creator in `JoinOrCreateRoom`.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum WinConditionKind {
+    #[default]
+    Dominance,
+    TargetSize {
+        #[serde(rename = "targetSize")]
+        target_size: f32,
+    },
+    LastStanding,
+    FixedRounds {
+        rounds: u64,
+    },
+    Timed {
+        #[serde(rename = "durationSecs")]
+        duration_secs: u64,
+    },
+}
+
+impl WinConditionKind {
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Self::Dominance | Self::LastStanding => true,
+            Self::TargetSize { target_size } => target_size.is_finite() && target_size > 0.0,
+            Self::FixedRounds { rounds } => rounds > 0,
+            Self::Timed { duration_secs } => (1..=MAX_TIMED_DURATION_SECS).contains(&duration_secs),
+        }
+    }
+
+    fn strategy(&self) -> Box<dyn WinCondition> {
+        match *self {
+            Self::Dominance => Box::new(Dominance),
+            Self::TargetSize { target_size } => Box::new(TargetSize { target_size }),
+            Self::LastStanding => Box::new(LastStanding),
+            Self::FixedRounds { rounds } => Box::new(FixedRounds { rounds }),
+            Self::Timed { duration_secs } => Box::new(Timed {
+                duration_ms: duration_secs.saturating_mul(1000),
+            }),
+        }
+    }
+}
+
+impl WinCondition for WinConditionKind {
+    fn match_winner(&self, room: &RoomState, now_ms: u64) -> Option<PlayerId> {
+        self.strategy().match_winner(room, now_ms)
+    }
+
+    fn deadline_ms(&self, room: &RoomState) -> Option<u64> {
+        self.strategy().deadline_ms(room)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::PlayerState;
+
+    fn room(sizes: &[f32]) -> RoomState {
+        let mut room = RoomState::new(
+            "ABCD".to_string(),
+            "keyboarding".to_string(),
+            WinConditionKind::Dominance,
+        );
+        for (idx, size) in sizes.iter().enumerate() {
+            let id = idx as PlayerId + 1;
+            room.players.insert(
+                id,
+                PlayerState {
+                    size: *size,
+                    ..PlayerState::new(id, format!("p{id}"), "#ffffff".to_string(), String::new())
+                },
+            );
+        }
+        room.next_player_id = sizes.len() as PlayerId + 1;
+        room
+    }
+
+    #[test]
+    fn target_size_picks_first_player_over_target() {
+        let condition = TargetSize { target_size: 40.0 };
+        assert_eq!(condition.match_winner(&room(&[39.0, 20.0]), 0), None);
+        assert_eq!(condition.match_winner(&room(&[41.0, 20.0]), 0), Some(1));
+    }
+
+    #[test]
+    fn last_standing_requires_opponents_to_be_gone() {
+        let mut room = room(&[10.0, 10.0]);
+        assert_eq!(LastStanding.match_winner(&room, 0), None);
+
+        room.eliminate_player(2, Some(1));
+        assert_eq!(LastStanding.match_winner(&room, 0), Some(1));
+
+        let solo = self::room(&[10.0]);
+        assert_eq!(LastStanding.match_winner(&solo, 0), None);
+    }
+
+    #[test]
+    fn fixed_rounds_waits_for_round_count_and_breaks_ties() {
+        let condition = FixedRounds { rounds: 3 };
+        let mut room = room(&[20.0, 12.0]);
+        room.round_id = 2;
+        assert_eq!(condition.match_winner(&room, 0), None);
+
+        room.round_id = 3;
+        assert_eq!(condition.match_winner(&room, 0), Some(1));
+
+        room.players.get_mut(&2).expect("player").size = 20.0;
+        assert_eq!(condition.match_winner(&room, 0), None);
+    }
+
+    #[test]
+    fn timed_match_ends_at_deadline() {
+        let condition = Timed {
+            duration_ms: 60_000,
+        };
+        let mut room = room(&[20.0, 12.0]);
+        assert_eq!(condition.deadline_ms(&room), None);
+
+        room.match_started_at_ms = Some(1_000);
+        assert_eq!(condition.deadline_ms(&room), Some(61_000));
+        assert_eq!(condition.match_winner(&room, 60_999), None);
+        assert_eq!(condition.match_winner(&room, 61_000), Some(1));
+
+        let endless = Timed {
+            duration_ms: u64::MAX,
+        };
+        assert_eq!(endless.deadline_ms(&room), Some(u64::MAX));
+    }
+
+    #[test]
+    fn dominance_is_the_default_kind() {
+        assert_eq!(WinConditionKind::default(), WinConditionKind::Dominance);
+        let room = room(&[30.0, 14.9]);
+        assert_eq!(WinConditionKind::Dominance.match_winner(&room, 0), Some(1));
+    }
+
+    #[test]
+    fn parses_win_condition_kinds() {
+        let parsed: WinConditionKind =
+            serde_json::from_str(r#"{"kind":"targetSize","targetSize":50}"#).expect("parse");
+        assert_eq!(parsed, WinConditionKind::TargetSize { target_size: 50.0 });
+
+        let parsed: WinConditionKind =
+            serde_json::from_str(r#"{"kind":"fixedRounds","rounds":5}"#).expect("parse");
+        assert_eq!(parsed, WinConditionKind::FixedRounds { rounds: 5 });
+
+        assert!(!WinConditionKind::FixedRounds { rounds: 0 }.is_valid());
+        assert!(
+            WinConditionKind::Timed {
+                duration_secs: MAX_TIMED_DURATION_SECS
+            }
+            .is_valid()
+        );
+        assert!(
+            !WinConditionKind::Timed {
+                duration_secs: u64::MAX
+            }
+            .is_valid()
+        );
+        assert!(serde_json::from_str::<WinConditionKind>(r#"{"kind":"sudden"}"#).is_err());
+    }
+}
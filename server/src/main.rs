@@ -1,19 +1,96 @@
+use core::game::{DEFAULT_MAX_PLAYERS, MIN_PLAYER_SIZE, WrongAnswerPenalty};
+use core::limits::ConnectionLimits;
 use core::{ServerConfig, run_server};
 use edif_io_arithmetic_adapter::ArithmeticAdapter;
 use edif_io_keyboarding_adapter::KeyboardingAdapter;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
+    tracing_subscriber::fmt::init();
     let bind_addr = std::env::var("BIND_ADDR").unwrap_or_else(|_| "0.0.0.0:4000".to_string());
     let growth_per_round_win = std::env::var("GROWTH_PER_ROUND_WIN")
         .ok()
         .and_then(|v| v.parse::<f32>().ok())
         .unwrap_or(4.0);
+    let wrong_answer_penalty = std::env::var("WRONG_ANSWER_PENALTY")
+        .ok()
+        .and_then(|v| v.parse::<WrongAnswerPenalty>().ok())
+        .unwrap_or_default();
+    let elimination_size = std::env::var("ELIMINATION_SIZE")
+        .ok()
+        .and_then(|v| v.parse::<f32>().ok())
+        .unwrap_or(MIN_PLAYER_SIZE);
+    let round_duration = std::env::var("ROUND_DURATION_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(Duration::from_secs);
+    let state_dir = std::env::var("STATE_DIR").ok().map(PathBuf::from);
+    let max_players_per_room = std::env::var("MAX_PLAYERS_PER_ROOM")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MAX_PLAYERS);
+    let mut limits = ConnectionLimits::default();
+    if let Some(max_text_chars) = std::env::var("MAX_TEXT_CHARS")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+    {
+        limits.max_text_chars = max_text_chars;
+    }
+    if let Some(max_frame_bytes) = std::env::var("MAX_FRAME_BYTES")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+    {
+        limits.max_frame_bytes = max_frame_bytes;
+    }
+    if let Some(max_queued_messages) = std::env::var("MAX_QUEUED_MESSAGES")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+    {
+        limits.max_queued_messages = max_queued_messages;
+    }
+    let progress_tick = std::env::var("PROGRESS_TICK_MS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(Duration::from_millis(50));
+    let ping_interval = std::env::var("PING_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(10));
+    let ping_timeout = std::env::var("PING_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(30));
+    let reconnect_grace = std::env::var("RECONNECT_GRACE_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(60));
+    let rejoin_token_ttl = std::env::var("REJOIN_TOKEN_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(12 * 60 * 60));
 
     let config = ServerConfig {
         bind_addr,
         growth_per_round_win,
+        wrong_answer_penalty,
+        elimination_size,
+        round_duration,
+        state_dir,
+        max_players_per_room,
+        limits,
+        progress_tick,
+        ping_interval,
+        ping_timeout,
+        reconnect_grace,
+        rejoin_token_ttl,
     };
     run_server(
         vec![Arc::new(KeyboardingAdapter), Arc::new(ArithmeticAdapter)],